@@ -0,0 +1,387 @@
+use crate::cache::{FileStamp, ScanCache, file_stamp};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+const OGG_PAGE_HEADER_LEN: usize = 27;
+
+pub fn hash_music_files(paths: &[&Path], cache: &mut ScanCache) -> HashMap<PathBuf, String> {
+    let mut hashes = HashMap::new();
+    let mut uncached: Vec<(&Path, Option<FileStamp>)> = Vec::new();
+
+    for &path in paths {
+        let stamp = file_stamp(path);
+        match stamp.and_then(|stamp| cache.audio_hash(path, stamp)) {
+            Some(hash) => {
+                hashes.insert(path.to_path_buf(), hash.to_string());
+            }
+            None => uncached.push((path, stamp)),
+        }
+    }
+
+    if uncached.is_empty() {
+        return hashes;
+    }
+
+    let pb = ProgressBar::new(uncached.len() as u64);
+    if let Ok(style) = ProgressStyle::default_bar()
+        .template("  [{bar:40.cyan/blue}] {pos}/{len} [{elapsed_precise}] {msg}")
+    {
+        pb.set_style(style.progress_chars("█▉▊▋▌▍▎▏  "));
+    }
+
+    let computed: Vec<(&Path, Option<FileStamp>, String)> = uncached
+        .par_iter()
+        .filter_map(|&(path, stamp)| {
+            if let Some(filename) = path.file_name() {
+                pb.set_message(filename.to_string_lossy().to_string());
+            }
+
+            let result = audio_hash(path);
+            pb.inc(1);
+            match result {
+                Ok(hash) => Some((path, stamp, hash)),
+                Err(e) => {
+                    pb.println(format!(
+                        "  Failed to hash audio of {}: {}",
+                        path.display(),
+                        e
+                    ));
+                    None
+                }
+            }
+        })
+        .collect();
+
+    pb.finish_and_clear();
+
+    for (path, stamp, hash) in computed {
+        if let Some(stamp) = stamp {
+            cache.insert_audio_hash(path, stamp, hash.clone());
+        }
+        hashes.insert(path.to_path_buf(), hash);
+    }
+
+    hashes
+}
+
+pub fn audio_hash(path: &Path) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    read_audio(path, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// The number of bytes of audio data in a file, leaving out tags, cover art
+/// and container headers.
+pub fn audio_payload_len(path: &Path) -> io::Result<u64> {
+    let mut len = 0;
+    read_audio(path, &mut len)?;
+    Ok(len)
+}
+
+/// Where the audio data of a file goes as it is read.
+trait AudioSink {
+    /// Takes the next `len` bytes from the current position of `file`.
+    fn consume(&mut self, file: &mut BufReader<File>, len: u64) -> io::Result<()>;
+}
+
+impl AudioSink for blake3::Hasher {
+    fn consume(&mut self, file: &mut BufReader<File>, len: u64) -> io::Result<()> {
+        io::copy(&mut file.by_ref().take(len), self)?;
+        Ok(())
+    }
+}
+
+/// Counting only needs the lengths, so the data itself is skipped.
+impl AudioSink for u64 {
+    fn consume(&mut self, file: &mut BufReader<File>, len: u64) -> io::Result<()> {
+        *self += len;
+        file.seek_relative(len as i64)
+    }
+}
+
+/// Passes the audio data of a file to `sink`, past any tags and headers.
+fn read_audio(path: &Path, sink: &mut impl AudioSink) -> io::Result<()> {
+    let mut file = BufReader::new(File::open(path)?);
+    let file_len = file.get_ref().metadata()?.len();
+
+    let start = skip_id3v2(&mut file)?;
+    file.seek(SeekFrom::Start(start))?;
+
+    let mut magic = [0u8; 12];
+    let read = read_up_to(&mut file, &mut magic)?;
+    let magic = &magic[..read];
+    file.seek(SeekFrom::Start(start))?;
+
+    if magic.starts_with(b"fLaC") {
+        flac_audio(&mut file, start, file_len, sink)?;
+    } else if magic.starts_with(b"OggS") {
+        ogg_audio(&mut file, sink)?;
+    } else if magic.len() >= 8 && &magic[4..8] == b"ftyp" {
+        mp4_audio(&mut file, start, file_len, sink)?;
+    } else if magic.starts_with(b"RIFF") && magic.len() >= 12 && &magic[8..12] == b"WAVE" {
+        chunk_audio(
+            &mut file,
+            start + 12,
+            file_len,
+            b"data",
+            Endian::Little,
+            sink,
+        )?;
+    } else if magic.starts_with(b"FORM")
+        && magic.len() >= 12
+        && matches!(&magic[8..12], b"AIFF" | b"AIFC")
+    {
+        chunk_audio(&mut file, start + 12, file_len, b"SSND", Endian::Big, sink)?;
+    } else {
+        let end = strip_trailing_tags(&mut file, start, file_len)?;
+        range_audio(&mut file, start, end, sink)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum Endian {
+    Little,
+    Big,
+}
+
+pub fn skip_id3v2(file: &mut BufReader<File>) -> io::Result<u64> {
+    let mut offset = 0;
+
+    loop {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 10];
+        if read_up_to(file, &mut header)? < 10 || &header[..3] != b"ID3" {
+            return Ok(offset);
+        }
+
+        let size = syncsafe(&header[6..10]);
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        offset += 10 + size + footer;
+    }
+}
+
+fn flac_audio(
+    file: &mut BufReader<File>,
+    start: u64,
+    file_len: u64,
+    sink: &mut impl AudioSink,
+) -> io::Result<()> {
+    let mut offset = start + 4;
+
+    loop {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header)?;
+
+        let is_last = header[0] & 0x80 != 0;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+        offset += 4 + length;
+
+        if is_last || offset >= file_len {
+            break;
+        }
+    }
+
+    let end = strip_trailing_tags(file, offset, file_len)?;
+    range_audio(file, offset, end, sink)
+}
+
+fn ogg_audio(file: &mut BufReader<File>, sink: &mut impl AudioSink) -> io::Result<()> {
+    let mut in_audio = false;
+
+    loop {
+        let mut header = [0u8; OGG_PAGE_HEADER_LEN];
+        if read_up_to(file, &mut header)? < OGG_PAGE_HEADER_LEN || &header[..4] != b"OggS" {
+            return Ok(());
+        }
+
+        let granule = u64::from_le_bytes(header[6..14].try_into().unwrap());
+        let mut segment_table = vec![0u8; header[26] as usize];
+        file.read_exact(&mut segment_table)?;
+        let body_len: u64 = segment_table.iter().map(|&len| len as u64).sum();
+
+        if !in_audio && granule != 0 && granule != u64::MAX {
+            in_audio = true;
+        }
+
+        if in_audio {
+            sink.consume(file, body_len)?;
+        } else {
+            file.seek_relative(body_len as i64)?;
+        }
+    }
+}
+
+fn mp4_audio(
+    file: &mut BufReader<File>,
+    start: u64,
+    file_len: u64,
+    sink: &mut impl AudioSink,
+) -> io::Result<()> {
+    let mut offset = start;
+
+    while offset + 8 <= file_len {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+
+        let mut size = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+        let mut header_len = 8;
+        if size == 1 {
+            let mut large = [0u8; 8];
+            file.read_exact(&mut large)?;
+            size = u64::from_be_bytes(large);
+            header_len = 16;
+        } else if size == 0 {
+            size = file_len - offset;
+        }
+        if size < header_len {
+            break;
+        }
+
+        if &header[4..8] == b"mdat" {
+            range_audio(file, offset + header_len, offset + size, sink)?;
+        }
+        offset += size;
+    }
+
+    Ok(())
+}
+
+fn chunk_audio(
+    file: &mut BufReader<File>,
+    mut offset: u64,
+    file_len: u64,
+    wanted: &[u8; 4],
+    endian: Endian,
+    sink: &mut impl AudioSink,
+) -> io::Result<()> {
+    while offset + 8 <= file_len {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+
+        let size_bytes = header[4..8].try_into().unwrap();
+        let size = match endian {
+            Endian::Little => u32::from_le_bytes(size_bytes),
+            Endian::Big => u32::from_be_bytes(size_bytes),
+        } as u64;
+
+        if &header[..4] == wanted {
+            let end = (offset + 8 + size).min(file_len);
+            range_audio(file, offset + 8, end, sink)?;
+        }
+        offset += 8 + size + (size & 1);
+    }
+
+    Ok(())
+}
+
+fn strip_trailing_tags(file: &mut (impl Read + Seek), start: u64, mut end: u64) -> io::Result<u64> {
+    loop {
+        if end >= start + 128 {
+            file.seek(SeekFrom::Start(end - 128))?;
+            let mut tag = [0u8; 3];
+            file.read_exact(&mut tag)?;
+            if &tag == b"TAG" {
+                end -= 128;
+                continue;
+            }
+        }
+
+        if end >= start + 32 {
+            file.seek(SeekFrom::Start(end - 32))?;
+            let mut footer = [0u8; 32];
+            file.read_exact(&mut footer)?;
+            if &footer[..8] == b"APETAGEX" {
+                let size = u32::from_le_bytes(footer[12..16].try_into().unwrap()) as u64;
+                let flags = u32::from_le_bytes(footer[20..24].try_into().unwrap());
+                let header = if flags & 0x8000_0000 != 0 { 32 } else { 0 };
+                // The size counts the footer, so anything smaller is broken
+                // and would leave the end where it is.
+                let stripped = end.saturating_sub(size + header).max(start);
+                if size < 32 || stripped >= end {
+                    return Ok(end);
+                }
+                end = stripped;
+                continue;
+            }
+        }
+
+        return Ok(end);
+    }
+}
+
+fn range_audio(
+    file: &mut BufReader<File>,
+    start: u64,
+    end: u64,
+    sink: &mut impl AudioSink,
+) -> io::Result<()> {
+    file.seek(SeekFrom::Start(start))?;
+    sink.consume(file, end.saturating_sub(start))
+}
+
+pub fn read_up_to(file: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn syncsafe(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0, |acc, &byte| (acc << 7) | (byte & 0x7f) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn ape_footer(size: u32, flags: u32) -> Vec<u8> {
+        let mut footer = b"APETAGEX".to_vec();
+        footer.extend(2000u32.to_le_bytes());
+        footer.extend(size.to_le_bytes());
+        footer.extend(0u32.to_le_bytes());
+        footer.extend(flags.to_le_bytes());
+        footer.extend([0u8; 8]);
+        footer
+    }
+
+    #[test]
+    fn strips_ape_tag_and_id3v1() {
+        let mut data = vec![0xAA; 100];
+        data.extend(ape_footer(32, 0));
+        data.extend(b"TAG");
+        data.extend([0u8; 125]);
+        let len = data.len() as u64;
+
+        let end = strip_trailing_tags(&mut Cursor::new(data), 0, len).unwrap();
+        assert_eq!(end, 100);
+    }
+
+    #[test]
+    fn zero_size_ape_footer_stops() {
+        let mut data = vec![0xAA; 100];
+        data.extend(ape_footer(0, 0));
+        let len = data.len() as u64;
+
+        let end = strip_trailing_tags(&mut Cursor::new(data), 0, len).unwrap();
+        assert_eq!(end, len);
+    }
+}
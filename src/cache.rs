@@ -0,0 +1,148 @@
+use crate::{fingerprint::Fingerprint, scan::AudioMetadata};
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+const CACHE_VERSION: u32 = 6;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ScanCache {
+    version: u32,
+    entries: HashMap<PathBuf, CacheEntry>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CacheEntry {
+    size: u64,
+    modified_ns: u64,
+    metadata: AudioMetadata,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    audio_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<Fingerprint>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    size: u64,
+    modified_ns: u64,
+}
+
+impl Default for ScanCache {
+    fn default() -> Self {
+        ScanCache {
+            version: CACHE_VERSION,
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl ScanCache {
+    pub fn load() -> ScanCache {
+        let Some(cache_path) = get_cache_path() else {
+            return ScanCache::default();
+        };
+
+        fs::read(&cache_path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<ScanCache>(&bytes).ok())
+            .filter(|cache| cache.version == CACHE_VERSION)
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let cache_path = get_cache_path().ok_or("Cache directory could not be found")?;
+
+        if let Some(parent) = cache_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let temp_path = cache_path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_vec(self)?)?;
+        fs::rename(&temp_path, &cache_path)?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, path: &Path, stamp: FileStamp) -> Option<&AudioMetadata> {
+        self.entries
+            .get(path)
+            .filter(|entry| entry.size == stamp.size && entry.modified_ns == stamp.modified_ns)
+            .map(|entry| &entry.metadata)
+    }
+
+    pub fn insert(&mut self, path: PathBuf, stamp: FileStamp, metadata: AudioMetadata) {
+        self.entries.insert(
+            path,
+            CacheEntry {
+                size: stamp.size,
+                modified_ns: stamp.modified_ns,
+                metadata,
+                audio_hash: None,
+                fingerprint: None,
+            },
+        );
+    }
+
+    pub fn audio_hash(&self, path: &Path, stamp: FileStamp) -> Option<&str> {
+        self.entries
+            .get(path)
+            .filter(|entry| entry.size == stamp.size && entry.modified_ns == stamp.modified_ns)
+            .and_then(|entry| entry.audio_hash.as_deref())
+    }
+
+    pub fn insert_audio_hash(&mut self, path: &Path, stamp: FileStamp, audio_hash: String) {
+        if let Some(entry) = self.entries.get_mut(path)
+            && entry.size == stamp.size
+            && entry.modified_ns == stamp.modified_ns
+        {
+            entry.audio_hash = Some(audio_hash);
+        }
+    }
+
+    pub fn fingerprint(&self, path: &Path, stamp: FileStamp) -> Option<&Fingerprint> {
+        self.entries
+            .get(path)
+            .filter(|entry| entry.size == stamp.size && entry.modified_ns == stamp.modified_ns)
+            .and_then(|entry| entry.fingerprint.as_ref())
+    }
+
+    pub fn insert_fingerprint(&mut self, path: &Path, stamp: FileStamp, fingerprint: Fingerprint) {
+        if let Some(entry) = self.entries.get_mut(path)
+            && entry.size == stamp.size
+            && entry.modified_ns == stamp.modified_ns
+        {
+            entry.fingerprint = Some(fingerprint);
+        }
+    }
+
+    pub fn retain_under(&mut self, root: &Path, keep: impl Fn(&Path) -> bool) {
+        self.entries
+            .retain(|path, _| !path.starts_with(root) || keep(path));
+    }
+}
+
+pub fn file_stamp(path: &Path) -> Option<FileStamp> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified_ns = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_nanos() as u64;
+
+    Some(FileStamp {
+        size: metadata.len(),
+        modified_ns,
+    })
+}
+
+fn get_cache_path() -> Option<PathBuf> {
+    let cache_dir = dirs::cache_dir()?;
+    Some(cache_dir.join("ufrume").join("scan-cache.json"))
+}
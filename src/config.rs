@@ -1,80 +1,824 @@
+use crate::{
+    infer::PathPattern,
+    template::{FIELDS, Template},
+};
+
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    ops::Range,
+    path::{Component, Path, PathBuf},
+};
+
+/// The layout of the config file. Bump it when a setting is renamed or changes
+/// meaning, and teach `migrate_config_file` how to upgrade from the old one.
+pub const CONFIG_VERSION: u32 = 1;
 
+/// Every section and setting falls back to its default when missing, so
+/// adding a setting does not break existing config files.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
+    #[serde(default = "current_version")]
+    pub version: u32,
+    #[serde(default)]
     pub organization: Organization,
+    #[serde(default)]
     pub rules: Rules,
+    #[serde(default)]
     pub formatting: Formatting,
+    #[serde(default)]
+    pub scan: Scan,
+}
+
+fn current_version() -> u32 {
+    CONFIG_VERSION
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Organization {
     pub structure: String,
+    // Leaving this out has always meant "no separate compilation layout".
+    #[serde(default)]
     pub compilation_structure: Option<String>,
     pub fallback_structure: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Rules {
-    pub handle_missing_metadata: String,
-    pub handle_duplicates: String,
+    pub handle_missing_metadata: MissingMetadataPolicy,
+    pub handle_duplicates: DuplicatePolicy,
+    pub duplicate_detection: DuplicateDetection,
+    pub fingerprint_threshold: f32,
+    pub duplicates_dir: Option<String>,
+    /// Placeholders whose values make up the duplicate key for `tags`
+    /// detection, such as `["isrc"]`. Unset means artist, album and title,
+    /// plus the track and disc numbers when tagged.
+    pub duplicate_key: Option<Vec<String>>,
+    pub mode: TransferMode,
+}
+
+/// What to do with files whose tags do not fill in the main structure.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingMetadataPolicy {
+    #[default]
+    Fallback,
+    Skip,
+}
+
+/// What to do with a file that duplicates one already claimed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    #[default]
+    Skip,
+    Rename,
+    Overwrite,
+    Quarantine,
+    KeepBest,
+}
+
+/// How two files are recognised as the same recording.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateDetection {
+    #[default]
+    Tags,
+    AudioHash,
+    Both,
+    Fingerprint,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferMode {
+    #[default]
+    Copy,
+    Move,
+    Hardlink,
+    Symlink,
+    Reflink,
+}
+
+impl TransferMode {
+    pub fn past_tense(self) -> &'static str {
+        match self {
+            TransferMode::Copy => "copied",
+            TransferMode::Move => "moved",
+            TransferMode::Hardlink => "hardlinked",
+            TransferMode::Symlink => "symlinked",
+            TransferMode::Reflink => "reflinked",
+        }
+    }
+}
+
+impl fmt::Display for TransferMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TransferMode::Copy => "Copy",
+            TransferMode::Move => "Move",
+            TransferMode::Hardlink => "Hardlink",
+            TransferMode::Symlink => "Symlink",
+            TransferMode::Reflink => "Reflink",
+        };
+        write!(f, "{}", name)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Formatting {
     pub replace_chars: HashMap<String, String>,
     pub max_filename_length: u8,
+    /// Give the organized copy the extension its contents call for when the
+    /// source has a wrong one or none at all.
+    pub fix_extensions: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Scan {
+    /// File extensions treated as music, without the leading dot. A profile
+    /// replaces the whole list, so it can leave formats out as well as add them.
+    pub extensions: Vec<String>,
+    /// Patterns like `{artist} - {album}/{track} - {title}` matched against
+    /// the end of each file's path to fill in missing tags. The first match wins.
+    pub path_patterns: Vec<String>,
+    pub path_precedence: PathPrecedence,
 }
 
-fn get_config_path() -> Result<PathBuf, String> {
-    let config_dir = dirs::config_dir().expect("Config directory could not be found");
+/// When metadata parsed from the path is used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathPrecedence {
+    /// Only for files without any tags; tagged files keep what they have.
+    #[default]
+    Tags,
+    /// For every field the tags leave empty.
+    FillGaps,
+}
+
+pub const LOCAL_CONFIG_FILE: &str = ".ufrume.toml";
+
+/// Environment variables starting with this override config keys, with `__`
+/// separating the parts of the key: `UFRUME_RULES__MODE=move`.
+pub const ENV_PREFIX: &str = "UFRUME_";
+
+/// Where to read the configuration from, which profile to apply and which
+/// `key=value` overrides to layer on top.
+#[derive(Debug, Default)]
+pub struct ConfigOptions<'a> {
+    pub path: Option<&'a Path>,
+    pub input_dir: Option<&'a Path>,
+    pub profile: Option<&'a str>,
+    pub overrides: &'a [String],
+}
+
+/// Where a setting was read from.
+#[derive(Debug, Clone)]
+pub enum Origin {
+    File { path: PathBuf, line: usize },
+    Env(String),
+    Flag,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::File { path, line } => write!(f, "{}:{}", path.display(), line),
+            Origin::Env(name) => write!(f, "env {}", name),
+            Origin::Flag => write!(f, "--set"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+    pub origin: Option<Origin>,
+}
+
+impl ConfigError {
+    fn new(key: &str, message: impl Into<String>) -> ConfigError {
+        ConfigError {
+            key: key.to_string(),
+            message: message.into(),
+            origin: None,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(origin) = &self.origin {
+            write!(f, "{}: ", origin)?;
+        }
+        if !self.key.is_empty() {
+            write!(f, "{}: ", self.key)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub fn get_config_path() -> Result<PathBuf, String> {
+    let config_dir = dirs::config_dir().ok_or("Config directory could not be found")?;
     Ok(config_dir.join("ufrume").join("config.toml"))
 }
 
-pub fn load_or_create_config() -> Result<Config, Box<dyn std::error::Error>> {
-    let config_path = get_config_path()?;
+/// Loads the explicit `--config` file, or the user config (created with the
+/// defaults if missing, and upgraded if written by an older release), then
+/// layers a `.ufrume.toml` from the input directory, the selected profile,
+/// `UFRUME_*` environment variables and `--set` overrides on top of it.
+/// Unknown keys are reported as warnings.
+pub fn load_or_create_config(
+    options: &ConfigOptions,
+) -> Result<Config, Box<dyn std::error::Error>> {
+    let loaded = load_layers(options)?;
+    for warning in loaded.warnings {
+        eprintln!("WARNING: {}", warning);
+    }
+    Ok(loaded.config)
+}
+
+/// Renders the effective configuration as TOML, with a comment after every
+/// setting naming the file, environment variable or flag it came from.
+pub fn describe_config(options: &ConfigOptions) -> Result<String, Box<dyn std::error::Error>> {
+    let loaded = load_layers(options)?;
+    for warning in loaded.warnings {
+        eprintln!("WARNING: {}", warning);
+    }
+
+    let mut text = toml::to_string_pretty(&loaded.config)?;
+    // Header tables start before their key, while values follow it.
+    let mut settings: Vec<(String, usize)> = spanned_keys(&text)
+        .into_iter()
+        .filter(|(_, key_span, value_span)| value_span.start > key_span.start)
+        .map(|(key, _, value_span)| (key, value_span.end))
+        .collect();
+    settings.sort_by_key(|(_, end)| std::cmp::Reverse(*end));
+
+    for (key, end) in settings {
+        let line_end = text[end..].find('\n').map_or(text.len(), |i| end + i);
+        let origin = match loaded.origins.get(&key) {
+            Some(origin) => origin.to_string(),
+            None => "default".to_string(),
+        };
+        text.insert_str(line_end, &format!("  # {}", origin));
+    }
+
+    Ok(text)
+}
+
+/// A merged configuration with where each of its keys came from.
+struct Loaded {
+    config: Config,
+    origins: HashMap<String, Origin>,
+    warnings: Vec<String>,
+}
+
+fn load_layers(options: &ConfigOptions) -> Result<Loaded, Box<dyn std::error::Error>> {
+    let mut warnings = Vec::new();
+    let (mut table, mut origins) = match options.path {
+        Some(path) => read_table(path)?,
+        None => {
+            let config_path = get_config_path()?;
+            if config_path.exists() {
+                migrate_config_file(&config_path)?;
+            } else {
+                write_default_config(&config_path)?;
+            }
+            read_table(&config_path)?
+        }
+    };
+
+    if let Some(input_dir) = options.input_dir {
+        let local_path = input_dir.join(LOCAL_CONFIG_FILE);
+        if local_path.is_file() {
+            let (local, local_origins) = read_table(&local_path)?;
+            merge_tables(&mut table, local);
+            origins.extend(local_origins);
+        }
+    }
+
+    let profiles = table.remove("profiles");
+    if let Some(name) = options.profile {
+        let profile = profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(name))
+            .and_then(|profile| profile.as_table())
+            .ok_or_else(|| format!("Unknown profile '{}'", name))?;
+        merge_tables(&mut table, profile.clone());
+
+        let prefix = format!("profiles.{}.", name);
+        let profile_origins: Vec<(String, Origin)> = origins
+            .iter()
+            .filter_map(|(key, origin)| {
+                let key = key.strip_prefix(&prefix)?;
+                Some((key.to_string(), origin.clone()))
+            })
+            .collect();
+        origins.extend(profile_origins);
+    }
+
+    let mut env: Vec<(String, String)> = std::env::vars()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+    env.sort();
+    for (name, value) in env {
+        let key = name[ENV_PREFIX.len()..]
+            .split("__")
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>()
+            .join(".");
+        set_key(&mut table, &key, parse_override(&value))
+            .map_err(|e| format!("{}: {}", name, e))?;
+        origins.insert(key, Origin::Env(name));
+    }
+
+    for setting in options.overrides {
+        let (key, value) = setting
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid --set '{}', expected key=value", setting))?;
+        let key = key.trim();
+        set_key(&mut table, key, parse_override(value))
+            .map_err(|e| format!("--set {}: {}", setting, e))?;
+        origins.insert(key.to_string(), Origin::Flag);
+    }
+
+    if config_version(&table) > CONFIG_VERSION {
+        let version = config_version(&table);
+        let message = format!(
+            "config version {} is newer than this release supports ({}), some settings may be ignored",
+            version, CONFIG_VERSION
+        );
+        warnings.push(match origins.get("version") {
+            Some(origin) => format!("{}: {}", origin, message),
+            None => message,
+        });
+    }
+
+    let config = deserialize_config(table.clone(), &origins)?;
+    config.validate().map_err(|mut e| {
+        e.origin = origins.get(&e.key).cloned();
+        e
+    })?;
+
+    // Every known key survives a round trip through `Config`, so whatever is
+    // lost on the way was not understood.
+    let known = config_table(&config)?;
+    for key in missing_keys(&table, &known, "") {
+        // An unknown table has no origin of its own, only its keys do.
+        let prefix = format!("{}.", key);
+        let origin = origins.get(&key).or_else(|| {
+            origins
+                .iter()
+                .filter(|(name, _)| name.starts_with(&prefix))
+                .min_by_key(|(name, _)| name.as_str())
+                .map(|(_, origin)| origin)
+        });
+        match origin {
+            Some(origin) => warnings.push(format!(
+                "{}: unknown config key '{}' is ignored",
+                origin, key
+            )),
+            None => warnings.push(format!("unknown config key '{}' is ignored", key)),
+        }
+    }
+
+    Ok(Loaded {
+        config,
+        origins,
+        warnings,
+    })
+}
+
+/// Reads an override as a TOML value, so numbers and booleans keep their
+/// type, and takes anything that is not one as a plain string.
+fn parse_override(value: &str) -> toml::Value {
+    value
+        .parse()
+        .unwrap_or_else(|_| toml::Value::String(value.to_string()))
+}
+
+/// Sets a dotted key, creating the tables on the way to it.
+fn set_key(table: &mut toml::Table, key: &str, value: toml::Value) -> Result<(), String> {
+    let parts: Vec<&str> = key.split('.').collect();
+    if parts.iter().any(|part| part.is_empty()) {
+        return Err(format!("invalid key '{}'", key));
+    }
+
+    let (last, parents) = parts.split_last().unwrap_or((&"", &[]));
+    let mut current = table;
+    for part in parents {
+        current = current
+            .entry(*part)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| format!("'{}' is not a table", part))?;
+    }
+    current.insert(last.to_string(), value);
+
+    Ok(())
+}
+
+fn config_version(table: &toml::Table) -> u32 {
+    table
+        .get("version")
+        .and_then(|version| version.as_integer())
+        .and_then(|version| u32::try_from(version).ok())
+        .unwrap_or(0)
+}
+
+/// Upgrades a config file written by an older release to `CONFIG_VERSION` in
+/// place, keeping the original next to it as `config.toml.v<N>.bak`. Settings
+/// the old file leaves out are written with the defaults they already took,
+/// so the upgrade does not change how files are organized.
+fn migrate_config_file(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let (table, _) = read_table(path)?;
+    let version = config_version(&table);
+    if version >= CONFIG_VERSION {
+        return Ok(());
+    }
+
+    // Version 0 files predate the version key and differ only in settings
+    // that have defaults. Renamed settings would be moved over here.
+
+    // Leave a file that does not load untouched, so the error points at the
+    // lines the user wrote.
+    let Ok(config) = deserialize_config(table, &HashMap::new()) else {
+        return Ok(());
+    };
+
+    // Edit the document rather than the parsed table, so the comments and
+    // layout the user wrote survive the upgrade.
+    let mut document: toml_edit::DocumentMut = fs::read_to_string(path)?.parse()?;
+    fill_missing(&mut document, config_table(&config)?)?;
+    document.insert("version", toml_edit::value(i64::from(CONFIG_VERSION)));
+    document.sort_values_by(|a, _, b, _| (a.get() != "version").cmp(&(b.get() != "version")));
+
+    let backup = path.with_extension(format!("toml.v{}.bak", version));
+    fs::copy(path, &backup)?;
+    fs::write(path, document.to_string())?;
+
+    eprintln!(
+        "  Upgraded {} to config version {}, the original was saved as {}",
+        path.display(),
+        CONFIG_VERSION,
+        backup.display()
+    );
+    Ok(())
+}
+
+/// Converts a config to a table by way of TOML text, so `f32` settings keep
+/// their short form instead of picking up `f64` rounding noise.
+fn config_table(config: &Config) -> Result<toml::Table, Box<dyn std::error::Error>> {
+    Ok(toml::from_str(&toml::to_string(config)?)?)
+}
+
+/// Adds the keys of `defaults` that `table` lacks, without touching the ones
+/// it has.
+fn fill_missing(
+    table: &mut toml_edit::Table,
+    defaults: toml::Table,
+) -> Result<(), toml_edit::TomlError> {
+    for (key, value) in defaults {
+        match (table.get_mut(&key), value) {
+            (Some(toml_edit::Item::Table(existing)), toml::Value::Table(value)) => {
+                fill_missing(existing, value)?
+            }
+            (Some(_), _) => {}
+            (None, toml::Value::Table(value)) => {
+                let mut missing = toml_edit::Table::new();
+                fill_missing(&mut missing, value)?;
+                table.insert(&key, toml_edit::Item::Table(missing));
+            }
+            (None, value) => {
+                table.insert(&key, toml_edit::Item::Value(value.to_string().parse()?));
+            }
+        }
+    }
+    Ok(())
+}
 
-    if let Some(parent) = config_path.parent() {
+/// Lists the dotted keys of `table` that `known` does not contain.
+fn missing_keys(table: &toml::Table, known: &toml::Table, prefix: &str) -> Vec<String> {
+    let mut missing = Vec::new();
+    for (key, value) in table {
+        let name = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match (value, known.get(key)) {
+            (_, None) => missing.push(name),
+            (toml::Value::Table(value), Some(toml::Value::Table(known))) => {
+                missing.extend(missing_keys(value, known, &name))
+            }
+            _ => {}
+        }
+    }
+    missing
+}
+
+pub fn write_default_config(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = path.parent() {
         fs::create_dir_all(parent)?;
     }
+    fs::write(path, toml::to_string_pretty(&Config::default())?)?;
+    Ok(())
+}
 
-    if config_path.exists() {
-        let config_str = fs::read_to_string(&config_path)?;
-        let config: Config = toml::from_str(&config_str)?;
-        Ok(config)
-    } else {
-        let default_config = Config::default();
-        let config_str = toml::to_string_pretty(&default_config)?;
-        fs::write(&config_path, config_str)?;
+/// The result of checking a config file that loaded successfully.
+pub struct Validation {
+    pub profiles: Vec<String>,
+    pub warnings: Vec<String>,
+}
 
-        Ok(default_config)
+/// Loads a configuration file on its own and with each of its profiles
+/// applied.
+pub fn validate_config_file(path: &Path) -> Result<Validation, Box<dyn std::error::Error>> {
+    let options = ConfigOptions {
+        path: Some(path),
+        ..Default::default()
+    };
+    let mut warnings = load_layers(&options)?.warnings;
+
+    let (table, _) = read_table(path)?;
+    let profiles: Vec<String> = table
+        .get("profiles")
+        .and_then(|profiles| profiles.as_table())
+        .map(|profiles| profiles.keys().cloned().collect())
+        .unwrap_or_default();
+
+    for profile in &profiles {
+        let options = ConfigOptions {
+            path: Some(path),
+            profile: Some(profile),
+            ..Default::default()
+        };
+        let loaded = load_layers(&options).map_err(|e| format!("profile '{}': {}", profile, e))?;
+        for warning in loaded.warnings {
+            if !warnings.contains(&warning) {
+                warnings.push(warning);
+            }
+        }
+    }
+
+    Ok(Validation { profiles, warnings })
+}
+
+/// Reads one configuration file along with the line each of its keys is on.
+fn read_table(
+    path: &Path,
+) -> Result<(toml::Table, HashMap<String, Origin>), Box<dyn std::error::Error>> {
+    let config_str = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let table = toml::from_str(&config_str).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let origins = spanned_keys(&config_str)
+        .into_iter()
+        .map(|(key, key_span, _)| {
+            let origin = Origin::File {
+                path: path.to_path_buf(),
+                line: config_str[..key_span.start].matches('\n').count() + 1,
+            };
+            (key, origin)
+        })
+        .collect();
+
+    Ok((table, origins))
+}
+
+/// Deserializes the merged table. It goes through TOML text rather than
+/// `toml::Value` because only then do errors carry a span, which is traced back
+/// to the offending key and from there to the file that set it.
+fn deserialize_config(
+    table: toml::Table,
+    origins: &HashMap<String, Origin>,
+) -> Result<Config, ConfigError> {
+    let text = toml::to_string(&table).map_err(|e| ConfigError::new("", e.to_string()))?;
+
+    toml::from_str(&text).map_err(|e: toml::de::Error| {
+        // Errors about a value point at the value, while errors about a table
+        // (such as a missing field) point at its header, which may also fall
+        // inside the span of an implicitly created parent table.
+        let keys = spanned_keys(&text);
+        let key = e
+            .span()
+            .and_then(|span| {
+                keys.iter()
+                    .rfind(|(_, key_span, _)| key_span.contains(&span.start))
+                    .or_else(|| {
+                        keys.iter()
+                            .rfind(|(_, _, value_span)| value_span.contains(&span.start))
+                    })
+            })
+            .map(|(key, _, _)| key.clone())
+            .unwrap_or_default();
+
+        ConfigError {
+            origin: origins.get(&key).cloned(),
+            key,
+            message: e.message().to_string(),
+        }
+    })
+}
+
+/// Lists every key in a TOML document as a dotted path with the spans of the
+/// key and its value, parents before their children.
+fn spanned_keys(text: &str) -> Vec<(String, Range<usize>, Range<usize>)> {
+    fn walk(
+        table: &toml::de::DeTable,
+        prefix: &str,
+        keys: &mut Vec<(String, Range<usize>, Range<usize>)>,
+    ) {
+        for (key, value) in table {
+            let name = if prefix.is_empty() {
+                key.get_ref().to_string()
+            } else {
+                format!("{}.{}", prefix, key.get_ref())
+            };
+            keys.push((name.clone(), key.span(), value.span()));
+            if let toml::de::DeValue::Table(inner) = value.get_ref() {
+                walk(inner, &name, keys);
+            }
+        }
+    }
+
+    let mut keys = Vec::new();
+    if let Ok(document) = toml::de::DeTable::parse(text) {
+        walk(document.get_ref(), "", &mut keys);
+    }
+    keys
+}
+
+/// Overlays `overrides` onto `base`, merging nested tables key by key so a
+/// profile only has to mention the settings it changes.
+fn merge_tables(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(value)) => {
+                merge_tables(existing, value)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let organization = &self.organization;
+        let mut templates = vec![
+            ("structure", &organization.structure),
+            ("fallback_structure", &organization.fallback_structure),
+        ];
+        if let Some(compilation_structure) = &organization.compilation_structure {
+            templates.push(("compilation_structure", compilation_structure));
+        }
+
+        for (name, template) in templates {
+            Template::check_relative(template)
+                .and_then(|_| Template::parse(template))
+                .map_err(|e| {
+                    ConfigError::new(
+                        &format!("organization.{}", name),
+                        format!("invalid template at {}", e),
+                    )
+                })?;
+        }
+
+        if let Some(fields) = &self.rules.duplicate_key {
+            if fields.is_empty() {
+                return Err(ConfigError::new(
+                    "rules.duplicate_key",
+                    "must list at least one placeholder",
+                ));
+            }
+            if let Some(field) = fields
+                .iter()
+                .find(|field| !FIELDS.contains(&field.as_str()))
+            {
+                return Err(ConfigError::new(
+                    "rules.duplicate_key",
+                    format!("unknown placeholder '{}'", field),
+                ));
+            }
+        }
+
+        // The duplicates folder has to sit inside the output directory, and
+        // not be the output directory itself.
+        if let Some(duplicates_dir) = &self.rules.duplicates_dir {
+            let inside = Template::check_relative(duplicates_dir).is_ok()
+                && Path::new(duplicates_dir)
+                    .components()
+                    .any(|component| matches!(component, Component::Normal(_)));
+            if !inside {
+                return Err(ConfigError::new(
+                    "rules.duplicates_dir",
+                    "must be a folder inside the output directory",
+                ));
+            }
+        }
+
+        for pattern in &self.scan.path_patterns {
+            PathPattern::parse(pattern).map_err(|e| {
+                ConfigError::new("scan.path_patterns", format!("invalid pattern at {}", e))
+            })?;
+        }
+
+        if self.scan.extensions.is_empty() {
+            return Err(ConfigError::new(
+                "scan.extensions",
+                "must list at least one extension",
+            ));
+        }
+
+        if !(0.0..=1.0).contains(&self.rules.fingerprint_threshold) {
+            return Err(ConfigError::new(
+                "rules.fingerprint_threshold",
+                "must be between 0.0 and 1.0",
+            ));
+        }
+
+        Ok(())
     }
 }
 
 impl Default for Config {
+    fn default() -> Self {
+        Config {
+            version: CONFIG_VERSION,
+            organization: Organization::default(),
+            rules: Rules::default(),
+            formatting: Formatting::default(),
+            scan: Scan::default(),
+        }
+    }
+}
+
+impl Default for Organization {
+    fn default() -> Self {
+        Organization {
+            structure: "{artist}/{year} - {album}/[?disctotal>1:Disc {disc}/]{track:02} - {title}"
+                .to_string(),
+            compilation_structure: Some(
+                "Compilations/{album}/[?disctotal>1:Disc {disc}/]{track:02} - {artist} - {title}"
+                    .to_string(),
+            ),
+            fallback_structure: "{filename}".to_string(),
+        }
+    }
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            handle_missing_metadata: MissingMetadataPolicy::Fallback,
+            handle_duplicates: DuplicatePolicy::Skip,
+            duplicate_detection: DuplicateDetection::Tags,
+            fingerprint_threshold: 0.85,
+            duplicates_dir: None,
+            duplicate_key: None,
+            mode: TransferMode::Copy,
+        }
+    }
+}
+
+impl Default for Formatting {
     fn default() -> Self {
         let mut replace_chars = HashMap::new();
         replace_chars.insert("/".to_string(), "-".to_string());
         replace_chars.insert(":".to_string(), "-".to_string());
         replace_chars.insert("?".to_string(), "".to_string());
 
-        Config {
-            organization: Organization {
-                structure: "{artist}/{year} - {album}/{track:02} - {title}".to_string(),
-                compilation_structure: Some(
-                    "Compilations/{album}/{track:02} - {artist} - {title}".to_string(),
-                ),
-                fallback_structure: "{filename}".to_string(),
-            },
-            rules: Rules {
-                handle_missing_metadata: "fallback".to_string(),
-                handle_duplicates: "skip".to_string(),
-            },
-            formatting: Formatting {
-                replace_chars: replace_chars,
-                max_filename_length: 255,
-            },
+        Formatting {
+            replace_chars,
+            max_filename_length: 255,
+            fix_extensions: false,
+        }
+    }
+}
+
+impl Default for Scan {
+    fn default() -> Self {
+        let extensions = [
+            "mp3", "flac", "m4a", "wav", "ogg", "aac", "opus", "wma", "aiff", "aif", "ape", "wv",
+            "dsf", "dff", "mpc",
+        ];
+
+        Scan {
+            extensions: extensions.iter().map(|e| e.to_string()).collect(),
+            path_patterns: Vec::new(),
+            path_precedence: PathPrecedence::Tags,
         }
     }
 }
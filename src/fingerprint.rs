@@ -0,0 +1,726 @@
+use crate::cache::{FileStamp, ScanCache, file_stamp};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use rustfft::{FftPlanner, num_complex::Complex};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    f32::consts::PI,
+    fs::File,
+    path::{Path, PathBuf},
+};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{CODEC_TYPE_NULL, DecoderOptions},
+    errors::Error as SymphoniaError,
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+// A port of Chromaprint's default fingerprint algorithm, from the resampler to
+// the classifiers. Decoders differ slightly, so the fingerprints come close to
+// those of `fpcalc` without always matching them bit for bit; they are only
+// ever compared with each other.
+const SAMPLE_RATE: u32 = 11025;
+const FRAME_SIZE: usize = 4096;
+const FRAME_STEP: usize = FRAME_SIZE / 3;
+const MAX_SECONDS: u64 = 120;
+const MIN_FREQ: f32 = 28.0;
+const MAX_FREQ: f32 = 3520.0;
+const CHROMA_BANDS: usize = 12;
+const CHROMA_FILTER: [f32; 5] = [0.25, 0.75, 1.0, 0.75, 0.25];
+const MAX_FILTER_WIDTH: usize = 16;
+
+// Resampler settings, as Chromaprint passes them to its copy of FFmpeg's
+// `av_resample`.
+const RESAMPLE_FILTER_LENGTH: usize = 16;
+const RESAMPLE_PHASE_SHIFT: u32 = 10;
+const RESAMPLE_CUTOFF: f64 = 0.8;
+const KAISER_BETA: f64 = 9.0;
+const FILTER_SHIFT: u32 = 15;
+// Input kept by the resampler before consumed samples are dropped.
+const RESAMPLE_BUFFER: usize = 32 * 1024;
+
+// Recordings whose lengths differ by more than this are never compared.
+const MAX_DURATION_DIFFERENCE: f32 = 5.0;
+// Largest alignment shift tried when comparing fingerprints, about ten seconds.
+const MAX_ALIGNMENT: usize = 80;
+
+struct Classifier {
+    filter: u8,
+    y: usize,
+    height: usize,
+    width: usize,
+    thresholds: [f32; 3],
+}
+
+const CLASSIFIERS: [Classifier; 16] = [
+    classifier(0, 4, 3, 15, [1.98215, 2.35817, 2.63523]),
+    classifier(4, 4, 6, 15, [-1.03809, -0.651211, -0.282167]),
+    classifier(1, 0, 4, 16, [-0.298702, 0.119262, 0.558497]),
+    classifier(3, 8, 2, 12, [-0.105439, 0.0153946, 0.135898]),
+    classifier(3, 4, 4, 8, [-0.142891, 0.0258736, 0.200632]),
+    classifier(4, 0, 3, 5, [-0.826319, -0.590612, -0.368214]),
+    classifier(1, 2, 2, 9, [-0.557409, -0.233035, 0.0534525]),
+    classifier(2, 7, 3, 4, [-0.0646826, 0.00620476, 0.0784847]),
+    classifier(2, 6, 2, 16, [-0.192387, -0.029699, 0.215855]),
+    classifier(2, 1, 3, 2, [-0.0397818, -0.00568076, 0.0292026]),
+    classifier(5, 10, 1, 15, [-0.53823, -0.369934, -0.190235]),
+    classifier(3, 6, 2, 10, [-0.124877, 0.0296483, 0.139239]),
+    classifier(2, 1, 1, 14, [-0.101475, 0.0225617, 0.231971]),
+    classifier(3, 5, 6, 4, [-0.0799915, -0.00729616, 0.063262]),
+    classifier(1, 9, 2, 12, [-0.272556, 0.019424, 0.302559]),
+    classifier(3, 4, 2, 14, [-0.164292, -0.0321188, 0.0846339]),
+];
+
+const fn classifier(
+    filter: u8,
+    y: usize,
+    height: usize,
+    width: usize,
+    thresholds: [f32; 3],
+) -> Classifier {
+    Classifier {
+        filter,
+        y,
+        height,
+        width,
+        thresholds,
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Fingerprint {
+    pub duration: f32,
+    #[serde(with = "packed")]
+    pub points: Vec<u32>,
+}
+
+/// Stores fingerprint points in the scan cache as base64 of their
+/// little-endian bytes, less than half the size of a JSON array of numbers.
+mod packed {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    pub fn serialize<S: Serializer>(points: &[u32], serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes: Vec<u8> = points
+            .iter()
+            .flat_map(|point| point.to_le_bytes())
+            .collect();
+        let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+        for chunk in bytes.chunks(3) {
+            let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+                group | u32::from(byte) << (16 - 8 * i)
+            });
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    text.push(ALPHABET[(group >> (18 - 6 * i) & 63) as usize] as char);
+                } else {
+                    text.push('=');
+                }
+            }
+        }
+        serializer.serialize_str(&text)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u32>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        let digits = text.trim_end_matches('=').as_bytes();
+
+        let mut bytes = Vec::with_capacity(digits.len() * 3 / 4);
+        for chunk in digits.chunks(4) {
+            let mut group = 0;
+            for (i, digit) in chunk.iter().enumerate() {
+                let value = ALPHABET
+                    .iter()
+                    .position(|c| c == digit)
+                    .ok_or_else(|| D::Error::custom("invalid base64 in fingerprint"))?;
+                group |= (value as u32) << (18 - 6 * i);
+            }
+            let count = chunk.len() * 6 / 8;
+            bytes.extend((0..count).map(|i| (group >> (16 - 8 * i)) as u8));
+        }
+
+        if bytes.len() % 4 != 0 {
+            return Err(D::Error::custom("truncated fingerprint"));
+        }
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect())
+    }
+}
+
+impl Fingerprint {
+    /// Groups recordings by length. Only fingerprints in the same or a
+    /// neighbouring bucket can be similar, so the rest need no comparing.
+    pub fn duration_bucket(&self) -> i64 {
+        (self.duration / MAX_DURATION_DIFFERENCE).floor() as i64
+    }
+
+    /// Fraction of matching bits between two fingerprints at their best alignment.
+    pub fn similarity(&self, other: &Fingerprint) -> f32 {
+        if (self.duration - other.duration).abs() > MAX_DURATION_DIFFERENCE {
+            return 0.0;
+        }
+
+        let (a, b) = (&self.points, &other.points);
+        let min_overlap = a.len().min(b.len()) / 2;
+        if min_overlap == 0 {
+            return 0.0;
+        }
+
+        let mut best = 0.0;
+        for shift in 0..=MAX_ALIGNMENT {
+            for (first, second) in [(a, b), (b, a)] {
+                if shift >= first.len() {
+                    continue;
+                }
+
+                let overlap = (first.len() - shift).min(second.len());
+                if overlap < min_overlap {
+                    continue;
+                }
+
+                let errors: u32 = first[shift..shift + overlap]
+                    .iter()
+                    .zip(&second[..overlap])
+                    .map(|(x, y)| (x ^ y).count_ones())
+                    .sum();
+                let similarity = 1.0 - errors as f32 / (overlap as f32 * 32.0);
+                if similarity > best {
+                    best = similarity;
+                }
+            }
+        }
+
+        best
+    }
+}
+
+pub fn fingerprint_music_files(
+    paths: &[&Path],
+    cache: &mut ScanCache,
+) -> HashMap<PathBuf, Fingerprint> {
+    let mut fingerprints = HashMap::new();
+    let mut uncached: Vec<(&Path, Option<FileStamp>)> = Vec::new();
+
+    for &path in paths {
+        let stamp = file_stamp(path);
+        match stamp.and_then(|stamp| cache.fingerprint(path, stamp)) {
+            Some(fingerprint) => {
+                fingerprints.insert(path.to_path_buf(), fingerprint.clone());
+            }
+            None => uncached.push((path, stamp)),
+        }
+    }
+
+    if uncached.is_empty() {
+        return fingerprints;
+    }
+
+    let pb = ProgressBar::new(uncached.len() as u64);
+    if let Ok(style) = ProgressStyle::default_bar()
+        .template("  [{bar:40.cyan/blue}] {pos}/{len} [{elapsed_precise}] {msg}")
+    {
+        pb.set_style(style.progress_chars("█▉▊▋▌▍▎▏  "));
+    }
+
+    let computed: Vec<(&Path, Option<FileStamp>, Fingerprint)> = uncached
+        .par_iter()
+        .filter_map(|&(path, stamp)| {
+            if let Some(filename) = path.file_name() {
+                pb.set_message(filename.to_string_lossy().to_string());
+            }
+
+            let result = fingerprint(path);
+            pb.inc(1);
+            match result {
+                Ok(fingerprint) => Some((path, stamp, fingerprint)),
+                Err(e) => {
+                    pb.println(format!("  Failed to fingerprint {}: {}", path.display(), e));
+                    None
+                }
+            }
+        })
+        .collect();
+
+    pb.finish_and_clear();
+
+    for (path, stamp, fingerprint) in computed {
+        if let Some(stamp) = stamp {
+            cache.insert_fingerprint(path, stamp, fingerprint.clone());
+        }
+        fingerprints.insert(path.to_path_buf(), fingerprint);
+    }
+
+    fingerprints
+}
+
+pub fn fingerprint(path: &Path) -> Result<Fingerprint, Box<dyn std::error::Error + Send + Sync>> {
+    let (samples, duration) = decode_mono(path)?;
+    let chroma = chroma_image(&samples);
+    let points = fingerprint_image(&chroma);
+
+    if points.is_empty() {
+        return Err("recording is too short to fingerprint".into());
+    }
+
+    Ok(Fingerprint { duration, points })
+}
+
+/// Decodes the first two minutes of the default track as 16-bit samples,
+/// downmixed to mono and resampled to the fingerprint sample rate, the way
+/// `fpcalc` feeds Chromaprint. Also returns the full duration.
+fn decode_mono(path: &Path) -> Result<(Vec<i16>, f32), Box<dyn std::error::Error + Send + Sync>> {
+    let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or("no audio track found")?;
+    let track_id = track.id;
+    let time_base = track.codec_params.time_base;
+    let mut total_frames = track.codec_params.n_frames;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut resampler = None;
+    let mut samples = Vec::new();
+    let mut decoded_frames = 0;
+    let mut packet_duration = 0;
+    let mut done = false;
+
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break;
+            }
+            Err(SymphoniaError::ResetRequired) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        packet_duration += packet.dur;
+        if done {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        let spec = *decoded.spec();
+        let channels = spec.channels.count();
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buffer.copy_interleaved_ref(decoded);
+
+        let resampler = resampler.get_or_insert_with(|| Resampler::new(spec.rate));
+        let limit = MAX_SECONDS * spec.rate as u64;
+        for frame in buffer.samples().chunks(channels) {
+            if decoded_frames == limit {
+                break;
+            }
+            let sum: i32 = frame.iter().map(|&sample| i32::from(to_i16(sample))).sum();
+            resampler.push((sum / channels as i32) as i16, &mut samples);
+            decoded_frames += 1;
+        }
+
+        if decoded_frames >= limit {
+            // Keep reading packets so the duration covers the whole recording.
+            done = true;
+            if total_frames.is_some() {
+                break;
+            }
+        }
+    }
+
+    let rate = resampler
+        .as_ref()
+        .map(|resampler| resampler.source_rate)
+        .ok_or("no audio could be decoded")?;
+    if total_frames.is_none() && time_base.is_none() {
+        total_frames = Some(decoded_frames);
+    }
+
+    let duration = match (total_frames, time_base) {
+        (Some(frames), _) => frames as f32 / rate as f32,
+        (None, Some(time_base)) => {
+            let time = time_base.calc_time(packet_duration);
+            time.seconds as f32 + time.frac as f32
+        }
+        (None, None) => 0.0,
+    };
+
+    Ok((samples, duration))
+}
+
+/// Converts a decoded sample the way FFmpeg does for `fpcalc`.
+fn to_i16(sample: f32) -> i16 {
+    (sample * 32768.0)
+        .round_ties_even()
+        .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+/// Streaming port of FFmpeg's polyphase `av_resample`: a Kaiser-windowed sinc
+/// low-pass filter with 2^10 phases, cut off at 80% of the lower Nyquist
+/// frequency. Input at the fingerprint sample rate passes straight through.
+struct Resampler {
+    source_rate: u32,
+    filter_length: usize,
+    filters: Vec<i16>,
+    input: Vec<i16>,
+    // Position of the next output in input samples, in fixed point with
+    // `RESAMPLE_PHASE_SHIFT` fraction bits, plus the remainder of the step.
+    index: i64,
+    frac: i64,
+}
+
+impl Resampler {
+    fn new(source_rate: u32) -> Self {
+        let factor = (SAMPLE_RATE as f64 * RESAMPLE_CUTOFF / source_rate as f64).min(1.0);
+        let filter_length = ((RESAMPLE_FILTER_LENGTH as f64 / factor).ceil() as usize).max(1);
+        let filters = if source_rate == SAMPLE_RATE {
+            Vec::new()
+        } else {
+            build_filters(factor, filter_length)
+        };
+
+        Resampler {
+            source_rate,
+            filter_length,
+            filters,
+            input: Vec::new(),
+            index: -(((filter_length - 1) / 2) as i64) << RESAMPLE_PHASE_SHIFT,
+            frac: 0,
+        }
+    }
+
+    fn push(&mut self, sample: i16, output: &mut Vec<i16>) {
+        if self.filters.is_empty() {
+            output.push(sample);
+            return;
+        }
+
+        self.input.push(sample);
+        let phase_mask = (1 << RESAMPLE_PHASE_SHIFT) - 1;
+        let dst_incr = (self.source_rate as i64) << RESAMPLE_PHASE_SHIFT;
+        let src_incr = SAMPLE_RATE as i64;
+
+        loop {
+            let sample_index = self.index >> RESAMPLE_PHASE_SHIFT;
+            if sample_index + self.filter_length as i64 > self.input.len() as i64 {
+                break;
+            }
+
+            let phase = (self.index & phase_mask) as usize;
+            let filter = &self.filters[phase * self.filter_length..][..self.filter_length];
+            // Taps before the first sample mirror the start of the input.
+            let value: i64 = filter
+                .iter()
+                .enumerate()
+                .map(|(i, &coefficient)| {
+                    let position = (sample_index + i as i64).unsigned_abs() as usize;
+                    i64::from(self.input[position]) * i64::from(coefficient)
+                })
+                .sum();
+            let value = (value + (1 << (FILTER_SHIFT - 1))) >> FILTER_SHIFT;
+            output.push(value.clamp(i16::MIN.into(), i16::MAX.into()) as i16);
+
+            self.index += dst_incr / src_incr;
+            self.frac += dst_incr % src_incr;
+            if self.frac >= src_incr {
+                self.frac -= src_incr;
+                self.index += 1;
+            }
+        }
+
+        let consumed = (self.index.max(0) >> RESAMPLE_PHASE_SHIFT) as usize;
+        if consumed >= RESAMPLE_BUFFER {
+            self.input.drain(..consumed);
+            self.index -= (consumed as i64) << RESAMPLE_PHASE_SHIFT;
+        }
+    }
+}
+
+/// Builds one low-pass filter per phase, each normalised to unit gain and
+/// quantized to `FILTER_SHIFT` fraction bits, like FFmpeg's `av_build_filter`.
+fn build_filters(factor: f64, filter_length: usize) -> Vec<i16> {
+    let phase_count = 1 << RESAMPLE_PHASE_SHIFT;
+    let center = (filter_length as i64 - 1) / 2;
+    let mut filters = Vec::with_capacity(phase_count * filter_length);
+    let mut taps = vec![0.0; filter_length];
+
+    for phase in 0..phase_count {
+        for (i, tap) in taps.iter_mut().enumerate() {
+            let x = std::f64::consts::PI
+                * ((i as i64 - center) as f64 - phase as f64 / phase_count as f64)
+                * factor;
+            let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+            let w = 2.0 * x / (factor * filter_length as f64 * std::f64::consts::PI);
+            *tap = sinc * bessel_i0(KAISER_BETA * (1.0 - w * w).max(0.0).sqrt());
+        }
+
+        let norm: f64 = taps.iter().sum();
+        filters.extend(taps.iter().map(|tap| {
+            (tap * (1 << FILTER_SHIFT) as f64 / norm)
+                .round_ties_even()
+                .clamp(i16::MIN.into(), i16::MAX.into()) as i16
+        }));
+    }
+
+    filters
+}
+
+/// Zeroth-order modified Bessel function of the first kind, summed until the
+/// series stops changing.
+fn bessel_i0(x: f64) -> f64 {
+    let x = x * x / 4.0;
+    let (mut value, mut last, mut term) = (1.0, 0.0, 1.0);
+    let mut i = 1.0;
+    while value != last {
+        last = value;
+        term *= x / (i * i);
+        value += term;
+        i += 1.0;
+    }
+    value
+}
+
+fn chroma_image(samples: &[i16]) -> Vec<[f32; CHROMA_BANDS]> {
+    if samples.len() < FRAME_SIZE {
+        return Vec::new();
+    }
+
+    // The Hamming window also scales the 16-bit samples down to [-1, 1].
+    let fft = FftPlanner::<f32>::new().plan_fft_forward(FRAME_SIZE);
+    let window: Vec<f32> = (0..FRAME_SIZE)
+        .map(|i| {
+            let hamming = 0.54 - 0.46 * (2.0 * PI * i as f32 / (FRAME_SIZE - 1) as f32).cos();
+            hamming / i16::MAX as f32
+        })
+        .collect();
+
+    let min_index = freq_to_index(MIN_FREQ).max(1);
+    let max_index = freq_to_index(MAX_FREQ).min(FRAME_SIZE / 2);
+    let notes: Vec<usize> = (0..max_index)
+        .map(|i| {
+            let freq = i as f32 * SAMPLE_RATE as f32 / FRAME_SIZE as f32;
+            let octave = (freq / (440.0 / 16.0)).log2();
+            ((CHROMA_BANDS as f32 * (octave - octave.floor())) as usize).min(CHROMA_BANDS - 1)
+        })
+        .collect();
+
+    let mut buffer = vec![Complex::new(0.0, 0.0); FRAME_SIZE];
+    let mut raw = Vec::new();
+
+    for start in (0..=samples.len() - FRAME_SIZE).step_by(FRAME_STEP) {
+        for (i, value) in buffer.iter_mut().enumerate() {
+            *value = Complex::new(f32::from(samples[start + i]) * window[i], 0.0);
+        }
+        fft.process(&mut buffer);
+
+        let mut features = [0.0; CHROMA_BANDS];
+        for i in min_index..max_index {
+            features[notes[i]] += buffer[i].norm_sqr();
+        }
+        raw.push(features);
+    }
+
+    raw.windows(CHROMA_FILTER.len())
+        .map(|rows| {
+            let mut features = [0.0; CHROMA_BANDS];
+            for (row, coefficient) in rows.iter().zip(CHROMA_FILTER) {
+                for (feature, value) in features.iter_mut().zip(row) {
+                    *feature += value * coefficient;
+                }
+            }
+
+            let norm = features.iter().map(|v| v * v).sum::<f32>().sqrt();
+            if norm < 0.01 {
+                [0.0; CHROMA_BANDS]
+            } else {
+                features.map(|v| v / norm)
+            }
+        })
+        .collect()
+}
+
+fn freq_to_index(freq: f32) -> usize {
+    (FRAME_SIZE as f32 * freq / SAMPLE_RATE as f32).round() as usize
+}
+
+fn fingerprint_image(image: &[[f32; CHROMA_BANDS]]) -> Vec<u32> {
+    if image.len() < MAX_FILTER_WIDTH {
+        return Vec::new();
+    }
+
+    // Summed-area table with an extra leading row and column of zeros.
+    let mut integral = vec![[0.0f64; CHROMA_BANDS + 1]; image.len() + 1];
+    for (x, row) in image.iter().enumerate() {
+        for y in 0..CHROMA_BANDS {
+            integral[x + 1][y + 1] =
+                row[y] as f64 + integral[x][y + 1] + integral[x + 1][y] - integral[x][y];
+        }
+    }
+    let area = |x1: usize, y1: usize, x2: usize, y2: usize| {
+        integral[x2][y2] - integral[x1][y2] - integral[x2][y1] + integral[x1][y1]
+    };
+
+    (0..=image.len() - MAX_FILTER_WIDTH)
+        .map(|x| {
+            CLASSIFIERS.iter().fold(0u32, |bits, classifier| {
+                let (y, w, h) = (classifier.y, classifier.width, classifier.height);
+                let (a, b) = match classifier.filter {
+                    0 => (area(x, y, x + w, y + h), 0.0),
+                    1 => {
+                        let h_2 = h / 2;
+                        (area(x, y + h_2, x + w, y + h), area(x, y, x + w, y + h_2))
+                    }
+                    2 => {
+                        let w_2 = w / 2;
+                        (area(x + w_2, y, x + w, y + h), area(x, y, x + w_2, y + h))
+                    }
+                    3 => {
+                        let (w_2, h_2) = (w / 2, h / 2);
+                        (
+                            area(x, y + h_2, x + w_2, y + h) + area(x + w_2, y, x + w, y + h_2),
+                            area(x, y, x + w_2, y + h_2) + area(x + w_2, y + h_2, x + w, y + h),
+                        )
+                    }
+                    4 => {
+                        let h_3 = h / 3;
+                        (
+                            area(x, y + h_3, x + w, y + 2 * h_3),
+                            area(x, y, x + w, y + h_3) + area(x, y + 2 * h_3, x + w, y + h),
+                        )
+                    }
+                    _ => {
+                        let w_3 = w / 3;
+                        (
+                            area(x + w_3, y, x + 2 * w_3, y + h),
+                            area(x, y, x + w_3, y + h) + area(x + 2 * w_3, y, x + w, y + h),
+                        )
+                    }
+                };
+
+                let value = ((1.0 + a).ln() - (1.0 + b).ln()) as f32;
+                let [low, middle, high] = classifier.thresholds;
+                // Quantized values are Gray coded so neighbouring levels differ by one bit.
+                let code = if value < low {
+                    0
+                } else if value < middle {
+                    1
+                } else if value < high {
+                    3
+                } else {
+                    2
+                };
+                (bits << 2) | code
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resample(source_rate: u32, freq: f32, seconds: u32) -> Vec<i16> {
+        let mut resampler = Resampler::new(source_rate);
+        let mut output = Vec::new();
+        for i in 0..source_rate * seconds {
+            let phase = 2.0 * PI * freq * i as f32 / source_rate as f32;
+            resampler.push(to_i16(0.5 * phase.sin()), &mut output);
+        }
+        output
+    }
+
+    fn peak(samples: &[i16]) -> i16 {
+        samples
+            .iter()
+            .map(|sample| sample.saturating_abs())
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn resamples_to_the_fingerprint_rate() {
+        let output = resample(44100, 440.0, 2);
+        assert!(output.len().abs_diff(2 * SAMPLE_RATE as usize) < 100);
+        // Passband tones keep their level...
+        assert!(peak(&output[1000..]).abs_diff(16384) < 300);
+        // ...and tones above the cutoff are filtered out instead of aliasing.
+        assert!(peak(&resample(44100, 6000.0, 2)[1000..]) < 300);
+    }
+
+    #[test]
+    fn packs_points_for_the_cache() {
+        for points in [vec![], vec![1], vec![0xdead_beef, 0, u32::MAX, 42, 7]] {
+            let fingerprint = Fingerprint {
+                duration: 1.0,
+                points: points.clone(),
+            };
+            let json = serde_json::to_string(&fingerprint).unwrap();
+            let parsed: Fingerprint = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed.points, points);
+        }
+
+        let json = r#"{"duration":1.0,"points":"AQAAAA=="}"#;
+        let parsed: Fingerprint = serde_json::from_str(json).unwrap();
+        assert_eq!(parsed.points, [1]);
+        assert!(serde_json::from_str::<Fingerprint>(r#"{"duration":1.0,"points":"AQA"}"#).is_err());
+    }
+
+    #[test]
+    fn passes_the_fingerprint_rate_through() {
+        let mut resampler = Resampler::new(SAMPLE_RATE);
+        let mut output = Vec::new();
+        for sample in [1, -2, 3] {
+            resampler.push(sample, &mut output);
+        }
+        assert_eq!(output, [1, -2, 3]);
+    }
+
+    #[test]
+    fn matches_resampled_copies_of_a_recording() {
+        let fingerprint = |rate: u32| {
+            let mut resampler = Resampler::new(rate);
+            let mut samples = Vec::new();
+            for i in 0..rate * 20 {
+                let t = i as f32 / rate as f32;
+                // A tone that steps through a few notes, so the chroma changes.
+                let freq = [220.0, 277.2, 329.6, 392.0][(t * 2.0) as usize % 4];
+                resampler.push(to_i16(0.3 * (2.0 * PI * freq * t).sin()), &mut samples);
+            }
+            Fingerprint {
+                duration: 20.0,
+                points: fingerprint_image(&chroma_image(&samples)),
+            }
+        };
+
+        assert!(fingerprint(44100).similarity(&fingerprint(48000)) > 0.9);
+    }
+}
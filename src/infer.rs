@@ -0,0 +1,288 @@
+use crate::{
+    config::{Config, PathPrecedence},
+    scan::AudioMetadata,
+    template::TemplateError,
+};
+
+use std::path::{Path, PathBuf};
+
+/// The placeholders a path pattern can fill in.
+pub const PATTERN_FIELDS: [&str; 10] = [
+    "artist",
+    "albumartist",
+    "album",
+    "title",
+    "year",
+    "track",
+    "tracktotal",
+    "disc",
+    "disctotal",
+    "genre",
+];
+
+const NUMERIC_PATTERN_FIELDS: [&str; 5] = ["year", "track", "tracktotal", "disc", "disctotal"];
+
+#[derive(Debug)]
+enum Token {
+    Text(String),
+    Field(&'static str),
+}
+
+/// A pattern such as `{artist} - {album}/{track} - {title}`, matched against
+/// the last components of a file's path with the extension left off.
+#[derive(Debug)]
+pub struct PathPattern {
+    components: Vec<Vec<Token>>,
+}
+
+impl PathPattern {
+    pub fn parse(pattern: &str) -> Result<PathPattern, TemplateError> {
+        let error = |column: usize, message: &str| TemplateError {
+            column,
+            message: message.to_string(),
+        };
+        if pattern.starts_with('/') {
+            return Err(error(1, "pattern must be a relative path"));
+        }
+
+        let mut components = Vec::new();
+        let mut column = 1;
+        for component in pattern.split('/') {
+            let mut tokens = Vec::new();
+            let mut rest = component;
+            while !rest.is_empty() {
+                let start = column + component[..component.len() - rest.len()].chars().count();
+                if let Some(inner) = rest.strip_prefix('{') {
+                    let end = inner
+                        .find('}')
+                        .ok_or_else(|| error(start, "unclosed placeholder"))?;
+                    let name = &inner[..end];
+                    let field = PATTERN_FIELDS
+                        .iter()
+                        .find(|field| **field == name)
+                        .ok_or_else(|| error(start, &format!("unknown placeholder '{}'", name)))?;
+                    if matches!(tokens.last(), Some(Token::Field(_))) {
+                        return Err(error(start, "placeholders need text between them"));
+                    }
+                    tokens.push(Token::Field(field));
+                    rest = &inner[end + 1..];
+                } else {
+                    let end = rest.find('{').unwrap_or(rest.len());
+                    tokens.push(Token::Text(rest[..end].to_string()));
+                    rest = &rest[end..];
+                }
+            }
+            if tokens.is_empty() {
+                return Err(error(column, "empty path component"));
+            }
+            column += component.chars().count() + 1;
+            components.push(tokens);
+        }
+
+        Ok(PathPattern { components })
+    }
+
+    /// The placeholder values when the end of `path` matches, or `None`.
+    fn captures(&self, path: &Path) -> Option<Vec<(&'static str, String)>> {
+        let mut names: Vec<String> = path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().to_string())
+            .collect();
+        if let Some(file_name) = names.last_mut()
+            && let Some(stem) = path.file_stem()
+        {
+            *file_name = stem.to_string_lossy().to_string();
+        }
+        if names.len() < self.components.len() {
+            return None;
+        }
+
+        let mut captures = Vec::new();
+        let names = &names[names.len() - self.components.len()..];
+        for (tokens, name) in self.components.iter().zip(names) {
+            match_tokens(tokens, name, &mut captures)?;
+        }
+        Some(captures)
+    }
+}
+
+/// Matches one path component, giving each placeholder the shortest text that
+/// lets the rest match. Numeric placeholders only take digits.
+fn match_tokens(
+    tokens: &[Token],
+    text: &str,
+    captures: &mut Vec<(&'static str, String)>,
+) -> Option<()> {
+    let Some((token, rest)) = tokens.split_first() else {
+        return text.is_empty().then_some(());
+    };
+
+    match token {
+        Token::Text(literal) => match_tokens(rest, text.strip_prefix(literal.as_str())?, captures),
+        Token::Field(field) => {
+            let numeric = NUMERIC_PATTERN_FIELDS.contains(field);
+            let ends = text
+                .char_indices()
+                .skip(1)
+                .map(|(index, _)| index)
+                .chain([text.len()]);
+            for end in ends {
+                let value = &text[..end];
+                if numeric && !value.bytes().all(|b| b.is_ascii_digit()) {
+                    break;
+                }
+                let len = captures.len();
+                captures.push((field, value.trim().to_string()));
+                if match_tokens(rest, &text[end..], captures).is_some() {
+                    return Some(());
+                }
+                captures.truncate(len);
+            }
+            None
+        }
+    }
+}
+
+/// Parses the `scan.path_patterns` of a config. `Config::validate` has
+/// already rejected invalid ones.
+pub fn path_patterns(config: &Config) -> Vec<PathPattern> {
+    config
+        .scan
+        .path_patterns
+        .iter()
+        .filter_map(|pattern| PathPattern::parse(pattern).ok())
+        .collect()
+}
+
+/// Runs `infer_from_path` on every file, with paths taken relative to `root`.
+pub fn infer_from_paths(files: &mut [(PathBuf, AudioMetadata)], root: &Path, config: &Config) {
+    let patterns = path_patterns(config);
+    for (path, metadata) in files {
+        infer_from_path(path, root, metadata, &patterns, config);
+    }
+}
+
+/// Fills in metadata from the first of `patterns` that matches the path
+/// relative to `root`, and records which fields came from it. With `tags`
+/// precedence only files without any tags are looked at; with `fill_gaps`
+/// every field the tags leave empty is.
+pub fn infer_from_path(
+    path: &Path,
+    root: &Path,
+    metadata: &mut AudioMetadata,
+    patterns: &[PathPattern],
+    config: &Config,
+) {
+    if config.scan.path_precedence == PathPrecedence::Tags && has_tags(metadata) {
+        return;
+    }
+    let path = path.strip_prefix(root).unwrap_or(path);
+    let Some(captures) = patterns.iter().find_map(|pattern| pattern.captures(path)) else {
+        return;
+    };
+
+    for (field, value) in captures {
+        if value.is_empty() {
+            continue;
+        }
+        let filled = match field {
+            "artist" => fill(&mut metadata.artist, Some(value.clone())),
+            "albumartist" => fill(&mut metadata.album_artist, Some(value.clone())),
+            "album" => fill(&mut metadata.album, Some(value.clone())),
+            "title" => fill(&mut metadata.title, Some(value.clone())),
+            "genre" => fill(&mut metadata.genre, Some(value.clone())),
+            "year" => fill(&mut metadata.year, value.parse().ok()),
+            "track" => fill(&mut metadata.track, value.parse().ok()),
+            "tracktotal" => fill(&mut metadata.track_total, value.parse().ok()),
+            "disc" => fill(&mut metadata.disc, value.parse().ok()),
+            "disctotal" => fill(&mut metadata.disc_total, value.parse().ok()),
+            _ => false,
+        };
+        if filled {
+            metadata.inferred.push(field.to_string());
+        }
+    }
+}
+
+fn fill<T>(slot: &mut Option<T>, value: Option<T>) -> bool {
+    if slot.is_some() || value.is_none() {
+        return false;
+    }
+    *slot = value;
+    true
+}
+
+fn has_tags(metadata: &AudioMetadata) -> bool {
+    metadata.title.is_some()
+        || metadata.artist.is_some()
+        || metadata.album.is_some()
+        || metadata.album_artist.is_some()
+        || metadata.year.is_some()
+        || metadata.track.is_some()
+        || metadata.disc.is_some()
+        || metadata.genre.is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captures(pattern: &str, path: &str) -> Option<Vec<(&'static str, String)>> {
+        PathPattern::parse(pattern)
+            .unwrap()
+            .captures(Path::new(path))
+    }
+
+    #[test]
+    fn backtracks_past_separators_in_values() {
+        let captures = captures(
+            "{track}. {artist} - {title}",
+            "Music/07. Simon - Garfunkel - The Boxer.mp3",
+        );
+        assert_eq!(
+            captures,
+            Some(vec![
+                ("track", "07".to_string()),
+                ("artist", "Simon".to_string()),
+                ("title", "Garfunkel - The Boxer".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn matches_the_last_components() {
+        let captures = captures(
+            "{artist}/{year} - {album}/{track} {title}",
+            "/in/Pop/Artist/1999 - Album/03 Song.flac",
+        );
+        assert_eq!(
+            captures,
+            Some(vec![
+                ("artist", "Artist".to_string()),
+                ("year", "1999".to_string()),
+                ("album", "Album".to_string()),
+                ("track", "03".to_string()),
+                ("title", "Song".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn numeric_fields_only_take_digits() {
+        assert_eq!(captures("{track} - {title}", "Intro - Song.mp3"), None);
+        assert_eq!(captures("{artist}/{title}", "Song.mp3"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        for pattern in [
+            "/{artist}",
+            "{artist",
+            "{nope}",
+            "{artist}{title}",
+            "{artist}//{title}",
+        ] {
+            assert!(PathPattern::parse(pattern).is_err(), "{}", pattern);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use crate::{config::TransferMode, transfer::transfer_file};
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JournalEntry {
+    CreateDir {
+        path: PathBuf,
+        timestamp: u64,
+    },
+    Transfer {
+        source: PathBuf,
+        target: PathBuf,
+        mode: TransferMode,
+        timestamp: u64,
+        size: u64,
+        modified_ns: u64,
+    },
+    Remove {
+        path: PathBuf,
+        timestamp: u64,
+    },
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+#[derive(Debug, Default)]
+pub struct UndoResult {
+    pub reverted: usize,
+    pub refused: usize,
+    pub failed: usize,
+    pub unrecoverable: usize,
+}
+
+impl Journal {
+    pub fn create(output_dir: &Path) -> io::Result<Journal> {
+        let journal_dir = output_dir.join(".ufrume");
+        fs::create_dir_all(&journal_dir)?;
+
+        let timestamp = now();
+        let mut path = journal_dir.join(format!("journal-{}.jsonl", timestamp));
+        let mut counter = 1;
+        let file = loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break file,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    path = journal_dir.join(format!("journal-{}-{}.jsonl", timestamp, counter));
+                    counter += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        Ok(Journal {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+
+        let mut missing = Vec::new();
+        let mut current = Some(dir);
+        while let Some(path) = current {
+            if path.as_os_str().is_empty() || path.exists() {
+                break;
+            }
+            missing.push(path.to_path_buf());
+            current = path.parent();
+        }
+
+        fs::create_dir_all(dir)?;
+
+        for path in missing.into_iter().rev() {
+            write_entry(
+                &mut file,
+                &JournalEntry::CreateDir {
+                    path: absolute(&path),
+                    timestamp: now(),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn record_transfer(
+        &self,
+        source: &Path,
+        target: &Path,
+        mode: TransferMode,
+    ) -> io::Result<()> {
+        let (size, modified_ns) = file_signature(target)?;
+        let entry = JournalEntry::Transfer {
+            source: absolute(source),
+            target: absolute(target),
+            mode,
+            timestamp: now(),
+            size,
+            modified_ns,
+        };
+        write_entry(&mut self.file.lock().unwrap(), &entry)
+    }
+
+    pub fn record_removal(&self, path: &Path) -> io::Result<()> {
+        let entry = JournalEntry::Remove {
+            path: absolute(path),
+            timestamp: now(),
+        };
+        write_entry(&mut self.file.lock().unwrap(), &entry)
+    }
+
+    pub fn finish(self) -> io::Result<Option<PathBuf>> {
+        let file = self.file.into_inner().unwrap();
+        let is_empty = file.metadata()?.len() == 0;
+        drop(file);
+
+        if is_empty {
+            fs::remove_file(&self.path)?;
+            Ok(None)
+        } else {
+            Ok(Some(self.path))
+        }
+    }
+}
+
+pub fn read_journal(journal_path: &Path) -> Result<Vec<JournalEntry>, Box<dyn std::error::Error>> {
+    let reader = BufReader::new(File::open(journal_path)?);
+    let mut entries = Vec::new();
+
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .map_err(|e| format!("Invalid journal entry on line {}: {}", line_number + 1, e))?;
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+pub fn undo_journal(journal_path: &Path) -> Result<UndoResult, Box<dyn std::error::Error>> {
+    let entries = read_journal(journal_path)?;
+    let mut result = UndoResult::default();
+
+    for entry in entries.iter().rev() {
+        match entry {
+            JournalEntry::Transfer {
+                source,
+                target,
+                mode,
+                size,
+                modified_ns,
+                ..
+            } => match file_signature(target) {
+                Ok(signature) if signature != (*size, *modified_ns) => {
+                    println!(
+                        "  {} changed since the run, left untouched",
+                        target.display()
+                    );
+                    result.refused += 1;
+                }
+                Ok(_) => match revert_transfer(source, target, *mode) {
+                    Ok(()) => result.reverted += 1,
+                    Err(e) => {
+                        println!("  Failed to revert {}: {}", target.display(), e);
+                        result.failed += 1;
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    println!(
+                        "  Failed to revert {}: it no longer exists",
+                        target.display()
+                    );
+                    result.failed += 1;
+                }
+                Err(e) => {
+                    println!("  Failed to inspect {}: {}", target.display(), e);
+                    result.failed += 1;
+                }
+            },
+            JournalEntry::CreateDir { path, .. } => {
+                let _ = fs::remove_dir(path);
+            }
+            JournalEntry::Remove { path, .. } => {
+                println!(
+                    "  {} was overwritten during the run and cannot be restored",
+                    path.display()
+                );
+                result.unrecoverable += 1;
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+fn revert_transfer(source: &Path, target: &Path, mode: TransferMode) -> io::Result<()> {
+    match mode {
+        TransferMode::Move => {
+            if fs::symlink_metadata(source).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", source.display()),
+                ));
+            }
+            if let Some(parent) = source.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            transfer_file(target, source, TransferMode::Move).map(|_| ())
+        }
+        TransferMode::Copy
+        | TransferMode::Hardlink
+        | TransferMode::Symlink
+        | TransferMode::Reflink => fs::remove_file(target),
+    }
+}
+
+fn file_signature(path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::symlink_metadata(path)?;
+    let modified_ns = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    Ok((metadata.len(), modified_ns))
+}
+
+/// Journal paths are stored absolute, so undo works from any directory.
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn write_entry(file: &mut File, entry: &JournalEntry) -> io::Result<()> {
+    let line = serde_json::to_string(entry)?;
+    writeln!(file, "{}", line)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
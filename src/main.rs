@@ -1,11 +1,51 @@
-use crate::{config::load_or_create_config, organize::organize_music_files, scan::scan_for_music};
-use clap::Parser;
+use crate::{
+    cache::ScanCache,
+    config::{
+        Config, ConfigOptions, TransferMode, describe_config, get_config_path,
+        load_or_create_config, validate_config_file, write_default_config,
+    },
+    infer::{infer_from_path, infer_from_paths, path_patterns},
+    journal::undo_journal,
+    organize::{organize_music_files, plan_music_files, render_template},
+    plan::PlannedAction,
+    report::{ReportEntry, attach_metadata, failed_entry, write_report},
+    scan::{extract_metadata, scan_for_music},
+    sniff::is_mismatched,
+    template::Template,
+    watch::watch_directory,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use console::style;
-use std::path::PathBuf;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+mod audiohash;
+mod cache;
 mod config;
+mod fingerprint;
+mod infer;
+mod journal;
 mod organize;
+mod plan;
+mod properties;
+mod quarantine;
+mod report;
 mod scan;
+mod sniff;
+mod tags;
+mod template;
+mod transfer;
+mod watch;
+
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_CONFIG: i32 = 3;
+const EXIT_PATHS: i32 = 4;
+const EXIT_FAILED: i32 = 5;
+const EXIT_NOTHING_TO_DO: i32 = 6;
 
 #[derive(Parser)]
 #[command(name = "ufrume")]
@@ -14,17 +54,151 @@ mod scan;
 )]
 #[command(author = "PandaDEV, contact@pandadev.net")]
 #[command(version = "1.0.0")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[command(after_help = "Exit codes:
+  0  success
+  1  unexpected error
+  2  invalid arguments
+  3  configuration error
+  4  input or output path error
+  5  files failed, or matched a --fail-on category (failed files always count)
+  6  no music files found")]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    organize: OrganizeArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Revert a previous run using the journal it wrote to the output directory
+    Undo { journal: PathBuf },
+    /// Watch the input directory and organize new music files as they arrive
+    Watch(WatchArgs),
+    /// Inspect, create and check configuration files
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the effective configuration after all layers are merged
+    Show {
+        #[command(flatten)]
+        config: ConfigArgs,
+        /// Include the .ufrume.toml from this input directory
+        #[arg(long)]
+        input_dir: Option<PathBuf>,
+    },
+    /// Print the location of the user config file
+    Path,
+    /// Write the default configuration to the user config file
+    Init {
+        /// Replace an existing config file
+        #[arg(long)]
+        force: bool,
+    },
+    /// Check a configuration file and each of its profiles for errors
+    Validate { file: PathBuf },
+    /// Render a template against the metadata of a single audio file
+    Preview {
+        template: String,
+        file: PathBuf,
+        /// Match path patterns against the file's path relative to this
+        /// directory, as organizing from it would [default: the current directory]
+        #[arg(long)]
+        input_dir: Option<PathBuf>,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+}
+
+#[derive(Args)]
+struct ConfigArgs {
+    /// Read the configuration from this file instead of the user config
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Apply the named [profiles.<name>] section on top of the configuration
+    #[arg(long)]
+    profile: Option<String>,
+    /// Override a config key, for example --set rules.handle_duplicates=rename
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    set: Vec<String>,
+}
+
+impl ConfigArgs {
+    fn options<'a>(&'a self, input_dir: Option<&'a Path>) -> ConfigOptions<'a> {
+        ConfigOptions {
+            path: self.config.as_deref(),
+            input_dir,
+            profile: self.profile.as_deref(),
+            overrides: &self.set,
+        }
+    }
+}
+
+#[derive(Args)]
+struct WatchArgs {
     input_dir: PathBuf,
     output_dir: PathBuf,
 
+    #[command(flatten)]
+    config: ConfigArgs,
+    #[arg(short, long, value_enum)]
+    mode: Option<TransferMode>,
+    #[arg(long, default_value_t = 2)]
+    settle_secs: u64,
+}
+
+#[derive(Args)]
+struct OrganizeArgs {
+    #[arg(required = true)]
+    input_dir: Option<PathBuf>,
+    #[arg(required = true)]
+    output_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    config: ConfigArgs,
     #[arg(short, long)]
     threads: Option<usize>,
     #[arg(short, long)]
     verbose: bool,
+    #[arg(short, long, value_enum)]
+    mode: Option<TransferMode>,
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    #[arg(long, requires = "dry_run")]
+    plan_file: Option<PathBuf>,
+    #[arg(long)]
+    rebuild_cache: bool,
+    #[arg(long, conflicts_with = "dry_run")]
+    report: Option<PathBuf>,
+    /// More outcomes that make the run exit with a failure code. Failed files
+    /// always do
+    #[arg(long, value_enum, value_delimiter = ',')]
+    fail_on: Vec<FailOn>,
 }
 
-fn verify_paths(input_dir: &PathBuf, output_dir: &PathBuf) -> Result<(), String> {
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum FailOn {
+    Failed,
+    Duplicates,
+    Fallback,
+    Skipped,
+    Mismatched,
+}
+
+#[derive(Default)]
+struct RunCounts {
+    failed: usize,
+    duplicates: usize,
+    fallback: usize,
+    skipped: usize,
+    mismatched: usize,
+}
+
+fn verify_paths(input_dir: &Path, output_dir: &Path) -> Result<(), String> {
     if !input_dir.exists() {
         return Err(format!(
             "Input path does not exist: {}",
@@ -40,9 +214,9 @@ fn verify_paths(input_dir: &PathBuf, output_dir: &PathBuf) -> Result<(), String>
     }
 
     if input_dir.components().as_path() == output_dir.components().as_path() {
-        return Err(format!(
-            "You are not allowed to specify the same path for both input and output"
-        ));
+        return Err(
+            "You are not allowed to specify the same path for both input and output".to_string(),
+        );
     }
 
     Ok(())
@@ -51,34 +225,221 @@ fn verify_paths(input_dir: &PathBuf, output_dir: &PathBuf) -> Result<(), String>
 fn main() {
     let cli = Cli::parse();
 
-    println!(
-        "{} {}",
-        style("[1/4]").bold().dim(),
-        "Loading configuration..."
-    );
-    let config = match load_or_create_config() {
+    match cli.command {
+        Some(Command::Undo { journal }) => undo(&journal),
+        Some(Command::Watch(args)) => watch(args),
+        Some(Command::Config(command)) => config(command),
+        None => organize(cli.organize),
+    }
+}
+
+fn load_config(args: &ConfigArgs, input_dir: Option<&Path>, mode: Option<TransferMode>) -> Config {
+    let mut config = match load_or_create_config(&args.options(input_dir)) {
         Ok(config) => config,
         Err(e) => {
             eprintln!("ERROR: Failed to load config: {}", e);
-            std::process::exit(1)
+            std::process::exit(EXIT_CONFIG)
         }
     };
 
-    println!("{} {}", style("[2/4]").bold().dim(), "Verifying paths...");
-    if let Err(e) = verify_paths(&cli.input_dir, &cli.output_dir) {
+    if let Some(mode) = mode {
+        config.rules.mode = mode;
+    }
+
+    config
+}
+
+fn watch(args: WatchArgs) {
+    println!("{} Loading configuration...", style("[1/3]").bold().dim());
+    let config = load_config(&args.config, Some(&args.input_dir), args.mode);
+
+    println!("{} Verifying paths...", style("[2/3]").bold().dim());
+    if let Err(e) = verify_paths(&args.input_dir, &args.output_dir) {
         eprintln!("ERROR: {}", e);
-        std::process::exit(1);
+        std::process::exit(EXIT_PATHS);
+    }
+
+    println!("  Input:  {}", style(args.input_dir.display()).green());
+    println!("  Output: {}", style(args.output_dir.display()).green());
+    println!("  Mode:   {}", style(config.rules.mode).cyan());
+    if let Some(profile) = &args.config.profile {
+        println!("  Profile: {}", style(profile).cyan());
     }
 
-    println!("  Input:  {}", style(cli.input_dir.display()).green());
-    println!("  Output: {}", style(cli.output_dir.display()).green());
+    println!(
+        "{} Watching for music files...",
+        style("[3/3]").bold().dim()
+    );
+    let settle = Duration::from_secs(args.settle_secs);
+    if let Err(e) = watch_directory(&args.input_dir, &args.output_dir, &config, settle) {
+        eprintln!("ERROR: Failed to watch input directory: {}", e);
+        std::process::exit(EXIT_ERROR);
+    }
+}
 
-    println!("  Mode:   {}", style("Copy").cyan());
+fn undo(journal: &Path) {
+    println!(
+        "{} Reverting {}...",
+        style("[1/1]").bold().dim(),
+        journal.display()
+    );
+
+    match undo_journal(journal) {
+        Ok(result) => {
+            println!("  {} operations reverted", result.reverted);
+            if result.refused > 0 {
+                println!("  {} files left untouched", result.refused);
+            }
+            if result.unrecoverable > 0 {
+                println!(
+                    "  {} overwritten files cannot be restored",
+                    result.unrecoverable
+                );
+            }
+            if result.failed > 0 {
+                println!("  {} operations failed", result.failed);
+                std::process::exit(EXIT_FAILED);
+            }
+        }
+        Err(e) => {
+            eprintln!("ERROR: Failed to undo run: {}", e);
+            std::process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+fn config(command: ConfigCommand) {
+    match command {
+        ConfigCommand::Show { config, input_dir } => {
+            match describe_config(&config.options(input_dir.as_deref())) {
+                Ok(text) => print!("{}", text),
+                Err(e) => {
+                    eprintln!("ERROR: Failed to load config: {}", e);
+                    std::process::exit(EXIT_CONFIG);
+                }
+            }
+        }
+        ConfigCommand::Path => match get_config_path() {
+            Ok(path) => println!("{}", path.display()),
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+                std::process::exit(EXIT_CONFIG);
+            }
+        },
+        ConfigCommand::Init { force } => {
+            let path = get_config_path().unwrap_or_else(|e| {
+                eprintln!("ERROR: {}", e);
+                std::process::exit(EXIT_CONFIG);
+            });
+            if path.exists() && !force {
+                eprintln!(
+                    "ERROR: {} already exists, use --force to replace it",
+                    path.display()
+                );
+                std::process::exit(EXIT_CONFIG);
+            }
+            if let Err(e) = write_default_config(&path) {
+                eprintln!("ERROR: Failed to write config: {}", e);
+                std::process::exit(EXIT_ERROR);
+            }
+            println!(
+                "  Wrote default config to {}",
+                style(path.display()).green()
+            );
+        }
+        ConfigCommand::Validate { file } => match validate_config_file(&file) {
+            Ok(validation) => {
+                for warning in validation.warnings {
+                    eprintln!("WARNING: {}", warning);
+                }
+                println!("  {} is valid", style(file.display()).green());
+                for profile in validation.profiles {
+                    println!("  Profile {} is valid", style(profile).cyan());
+                }
+            }
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+                std::process::exit(EXIT_CONFIG);
+            }
+        },
+        ConfigCommand::Preview {
+            template,
+            file,
+            input_dir,
+            config,
+        } => preview(&template, &file, input_dir.as_deref(), &config),
+    }
+}
+
+fn preview(template: &str, file: &Path, input_dir: Option<&Path>, args: &ConfigArgs) {
+    let config = load_config(args, input_dir, None);
+
+    if let Err(e) = Template::check_relative(template).and_then(|_| Template::parse(template)) {
+        eprintln!("ERROR: Invalid template: {}", e);
+        eprintln!("  {}", template);
+        eprintln!("  {}^", " ".repeat(e.column - 1));
+        std::process::exit(EXIT_CONFIG);
+    }
+
+    let mut metadata = match extract_metadata(file) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            eprintln!(
+                "ERROR: Failed to read metadata of {}: {}",
+                file.display(),
+                e
+            );
+            std::process::exit(EXIT_PATHS);
+        }
+    };
+
+    // Organizing matches absolute paths against the absolute input
+    // directory, so do the same here.
+    let root = std::path::absolute(input_dir.unwrap_or(Path::new("."))).unwrap_or_default();
+    let path = std::path::absolute(file).unwrap_or_else(|_| file.to_path_buf());
+    infer_from_path(
+        &path,
+        &root,
+        &mut metadata,
+        &path_patterns(&config),
+        &config,
+    );
+
+    match render_template(template, file, &metadata, &config) {
+        Some(path) => println!("{}", path.display()),
+        None => {
+            eprintln!("ERROR: The file is missing metadata the template requires");
+            std::process::exit(EXIT_FAILED);
+        }
+    }
+}
+
+fn organize(cli: OrganizeArgs) {
+    let (Some(input_dir), Some(output_dir)) = (cli.input_dir, cli.output_dir) else {
+        unreachable!("input and output directories are required arguments");
+    };
+
+    println!("{} Loading configuration...", style("[1/4]").bold().dim());
+    let config = load_config(&cli.config, Some(&input_dir), cli.mode);
+
+    println!("{} Verifying paths...", style("[2/4]").bold().dim());
+    if let Err(e) = verify_paths(&input_dir, &output_dir) {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(EXIT_PATHS);
+    }
+
+    println!("  Input:  {}", style(input_dir.display()).green());
+    println!("  Output: {}", style(output_dir.display()).green());
+
+    println!("  Mode:   {}", style(config.rules.mode).cyan());
+    if let Some(profile) = &cli.config.profile {
+        println!("  Profile: {}", style(profile).cyan());
+    }
 
     if let Some(count) = cli.threads {
         if count == 0 {
             eprintln!("ERROR: Thread count must be greater than 0");
-            std::process::exit(1);
+            std::process::exit(EXIT_USAGE);
         }
 
         rayon::ThreadPoolBuilder::new()
@@ -86,27 +447,46 @@ fn main() {
             .build_global()
             .map_err(|e| {
                 eprintln!("ERROR: Failed to configure thread pool: {}", e);
-                std::process::exit(1);
+                std::process::exit(EXIT_ERROR);
             })
             .unwrap();
         println!("  Threads: {}", style(count.to_string()).cyan());
     }
 
-    println!(
-        "{} {}",
-        style("[3/4]").bold().dim(),
-        "Scanning music files..."
-    );
+    println!("{} Scanning music files...", style("[3/4]").bold().dim());
+
+    let mut cache = if cli.rebuild_cache {
+        ScanCache::default()
+    } else {
+        ScanCache::load()
+    };
+
+    let scan = scan_for_music(&input_dir, &config.scan.extensions, &mut cache);
+    let (mut music_files, scan_failures) = match scan {
+        Ok(scan) => {
+            let failures: Vec<ReportEntry> = scan
+                .failures
+                .iter()
+                .map(|(path, error)| failed_entry(path, error))
+                .collect();
+            let music_files = scan.files;
 
-    let music_files = match scan_for_music(&cli.input_dir) {
-        Ok(music_files) => {
             if music_files.is_empty() {
                 println!("No music files found to organize.");
-                return;
+                save_cache(&cache);
+                if let Some(report) = &cli.report {
+                    write_run_report(report, config.rules.mode, &failures);
+                }
+                let counts = RunCounts {
+                    failed: failures.len(),
+                    ..RunCounts::default()
+                };
+                check_fail_on(&cli.fail_on, &counts);
+                std::process::exit(EXIT_NOTHING_TO_DO);
             } else {
                 if cli.verbose {
                     println!("\nScan Results:");
-                    for (_i, (path, metadata)) in music_files.iter().enumerate().take(5) {
+                    for (path, metadata) in music_files.iter().take(5) {
                         println!(
                             "  {} - {} - {}",
                             metadata.artist.as_deref().unwrap_or("Unknown Artist"),
@@ -118,26 +498,162 @@ fn main() {
                         println!("  ... and {} more files", music_files.len() - 5);
                     }
                 }
-                music_files
+                (music_files, failures)
             }
         }
         Err(e) => {
             eprintln!("ERROR: Failed to scan music files: {}", e);
-            std::process::exit(1);
+            std::process::exit(EXIT_ERROR);
         }
     };
 
+    let root = std::path::absolute(&input_dir).unwrap_or_else(|_| input_dir.clone());
+    infer_from_paths(&mut music_files, &root, &config);
+
+    let mut mismatched = 0;
+    for (path, metadata) in &music_files {
+        if let Some(format) = &metadata.format
+            && is_mismatched(path, format)
+        {
+            let extension = match path.extension() {
+                Some(extension) => format!("a .{} extension", extension.to_string_lossy()),
+                None => "no extension".to_string(),
+            };
+            eprintln!(
+                "  Extension mismatch: {} has {} but contains {} data",
+                path.display(),
+                extension,
+                format
+            );
+            mismatched += 1;
+        }
+    }
+
+    if cli.dry_run {
+        println!(
+            "\n{} Planning music files (dry run)...",
+            style("[4/4]").bold().dim()
+        );
+
+        let plan = plan_music_files(&music_files, &output_dir, &config, &mut cache);
+        save_cache(&cache);
+
+        match &cli.plan_file {
+            Some(plan_file) => {
+                if let Err(e) = fs::write(plan_file, plan.to_string()) {
+                    eprintln!("ERROR: Failed to write plan: {}", e);
+                    std::process::exit(EXIT_ERROR);
+                }
+                println!("  Plan written to {}", style(plan_file.display()).green());
+            }
+            None => {
+                for line in plan.to_string().lines() {
+                    println!("  {}", line);
+                }
+            }
+        }
+
+        println!(
+            "  {} files would be {}",
+            plan.transfers(),
+            plan.mode.past_tense()
+        );
+        let skipped = plan.count(|action| matches!(action, PlannedAction::Skip));
+        if skipped > 0 {
+            println!("  {} files would be skipped", skipped);
+        }
+        let duplicates = plan.count(|action| {
+            matches!(
+                action,
+                PlannedAction::Duplicate { .. }
+                    | PlannedAction::Rename { .. }
+                    | PlannedAction::Quarantine { .. }
+            )
+        });
+        if duplicates > 0 {
+            println!("  {} duplicates would be handled", duplicates);
+        }
+
+        let counts = RunCounts {
+            failed: scan_failures.len(),
+            duplicates,
+            fallback: plan.operations.iter().filter(|op| op.fallback).count(),
+            skipped,
+            mismatched,
+        };
+        check_fail_on(&cli.fail_on, &counts);
+        return;
+    }
+
     println!(
-        "\n{} {}",
-        style("[4/4]").bold().dim(),
-        "Organizing music files..."
+        "\n{} Organizing music files...",
+        style("[4/4]").bold().dim()
     );
 
-    match organize_music_files(&music_files, &cli.output_dir, &config) {
-        Ok(_result) => (),
+    let result = organize_music_files(&music_files, &output_dir, &config, &mut cache);
+    save_cache(&cache);
+
+    match result {
+        Ok(mut result) => {
+            let counts = RunCounts {
+                failed: result.failed + scan_failures.len(),
+                duplicates: result.duplicates,
+                fallback: result.fallback,
+                skipped: result.skipped,
+                mismatched,
+            };
+
+            if let Some(report) = &cli.report {
+                attach_metadata(&mut result.entries, &music_files);
+                result.entries.extend(scan_failures);
+                write_run_report(report, config.rules.mode, &result.entries);
+            }
+
+            check_fail_on(&cli.fail_on, &counts);
+        }
         Err(e) => {
             eprintln!("ERROR: Failed to organize music files: {}", e);
-            std::process::exit(1);
+            std::process::exit(EXIT_ERROR);
         }
     }
 }
+
+fn check_fail_on(fail_on: &[FailOn], counts: &RunCounts) {
+    let triggered: Vec<String> = [FailOn::Failed]
+        .iter()
+        .chain(
+            fail_on
+                .iter()
+                .filter(|&&category| category != FailOn::Failed),
+        )
+        .filter_map(|category| {
+            let (count, label) = match category {
+                FailOn::Failed => (counts.failed, "failed"),
+                FailOn::Duplicates => (counts.duplicates, "duplicates"),
+                FailOn::Fallback => (counts.fallback, "fallback"),
+                FailOn::Skipped => (counts.skipped, "skipped"),
+                FailOn::Mismatched => (counts.mismatched, "mismatched"),
+            };
+            (count > 0).then(|| format!("{} {}", count, label))
+        })
+        .collect();
+
+    if !triggered.is_empty() {
+        eprintln!("ERROR: Run failed (--fail-on): {}", triggered.join(", "));
+        std::process::exit(EXIT_FAILED);
+    }
+}
+
+fn write_run_report(path: &Path, mode: TransferMode, entries: &[ReportEntry]) {
+    if let Err(e) = write_report(path, mode, entries) {
+        eprintln!("ERROR: Failed to write report: {}", e);
+        std::process::exit(EXIT_ERROR);
+    }
+    println!("  Report written to {}", style(path.display()).green());
+}
+
+fn save_cache(cache: &ScanCache) {
+    if let Err(e) = cache.save() {
+        eprintln!("WARNING: Failed to save scan cache: {}", e);
+    }
+}
@@ -1,21 +1,36 @@
-use crate::{config::Config, scan::AudioMetadata};
+use crate::{
+    audiohash::hash_music_files,
+    cache::ScanCache,
+    config::{Config, DuplicateDetection, DuplicatePolicy, MissingMetadataPolicy, TransferMode},
+    fingerprint::{Fingerprint, fingerprint_music_files},
+    journal::Journal,
+    plan::{Plan, PlannedAction, PlannedOperation},
+    properties::{AudioProperties, Quality},
+    quarantine::{DEFAULT_QUARANTINE_DIR, MANIFEST_FILE, Manifest},
+    report::{Outcome, ReportEntry},
+    scan::AudioMetadata,
+    sniff::is_mismatched,
+    template::{Template, Value},
+    transfer::{TransferOutcome, transfer_file},
+};
 
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
 use std::{
-    collections::HashMap,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
     fs,
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
     time::Instant,
 };
 
+/// The tag values two files must share to count as duplicates. `copy` tells
+/// apart the numbered copies the rename policy keeps of the same key.
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 struct MetadataKey {
-    artist: String,
-    album: String,
-    title: String,
-    track: Option<u16>,
+    values: Vec<Option<String>>,
+    copy: usize,
 }
 
 #[derive(Debug)]
@@ -24,12 +39,479 @@ pub struct OrganizeResult {
     pub skipped: usize,
     pub failed: usize,
     pub duplicates: usize,
+    pub fallback: usize,
+    pub entries: Vec<ReportEntry>,
+}
+
+#[derive(Default)]
+struct AudioIdentity {
+    hash: Option<String>,
+    fingerprint: Option<Fingerprint>,
+}
+
+#[derive(Debug)]
+struct Claim {
+    path: PathBuf,
+    operation: Option<usize>,
+    quality: Quality,
+}
+
+impl Claim {
+    fn new(path: PathBuf, operation: Option<usize>, metadata: &AudioMetadata) -> Self {
+        Claim {
+            path,
+            operation,
+            quality: Quality::of(metadata),
+        }
+    }
+}
+
+pub struct Planner<'a> {
+    output_dir: &'a Path,
+    config: &'a Config,
+    claims: Vec<Claim>,
+    by_metadata: HashMap<MetadataKey, usize>,
+    by_target: HashMap<PathBuf, usize>,
+    by_audio_hash: HashMap<String, usize>,
+    fingerprints: HashMap<i64, Vec<(usize, Fingerprint)>>,
+    // The bucket each claim's fingerprint is filed under, so replacing it only
+    // touches that bucket.
+    fingerprint_buckets: HashMap<usize, i64>,
+    quarantined: HashSet<PathBuf>,
+    planned: usize,
+}
+
+impl<'a> Planner<'a> {
+    pub fn new(output_dir: &'a Path, config: &'a Config, cache: &mut ScanCache) -> Self {
+        let mut planner = Planner {
+            output_dir,
+            config,
+            claims: Vec::new(),
+            by_metadata: HashMap::new(),
+            by_target: HashMap::new(),
+            by_audio_hash: HashMap::new(),
+            fingerprints: HashMap::new(),
+            fingerprint_buckets: HashMap::new(),
+            quarantined: HashSet::new(),
+            planned: 0,
+        };
+
+        if let Ok(scan) = crate::scan::scan_for_music(output_dir, &config.scan.extensions, cache) {
+            let mut existing_files = scan.files;
+            if let Some(duplicates_dir) = planner.duplicates_dir() {
+                existing_files.retain(|(path, _)| !path.starts_with(&duplicates_dir));
+            }
+            let mut identities = planner.audio_identities(&existing_files, cache);
+
+            for (path, metadata) in existing_files {
+                let metadata_key = create_metadata_key(&path, &metadata, config);
+                let identity = identities.remove(&path).unwrap_or_default();
+                let claim = planner.claim(Claim::new(path.clone(), None, &metadata));
+                planner.register(claim, metadata_key, identity);
+            }
+        }
+
+        planner
+    }
+
+    pub fn plan(
+        &mut self,
+        music_files: &[(PathBuf, AudioMetadata)],
+        cache: &mut ScanCache,
+    ) -> Plan {
+        let mut plan = Plan {
+            mode: self.config.rules.mode,
+            operations: Vec::with_capacity(music_files.len()),
+            manifest: self
+                .duplicates_dir()
+                .map(|duplicates_dir| duplicates_dir.join(MANIFEST_FILE)),
+        };
+
+        let mut identities = self.audio_identities(music_files, cache);
+        for (source_path, metadata) in music_files {
+            let identity = identities.remove(source_path).unwrap_or_default();
+            self.plan_file(source_path, metadata, identity, &mut plan);
+        }
+
+        self.planned += plan.operations.len();
+        plan
+    }
+
+    fn audio_identities(
+        &self,
+        music_files: &[(PathBuf, AudioMetadata)],
+        cache: &mut ScanCache,
+    ) -> HashMap<PathBuf, AudioIdentity> {
+        let mut identities: HashMap<PathBuf, AudioIdentity> = HashMap::new();
+        let paths: Vec<&Path> = music_files.iter().map(|(path, _)| path.as_path()).collect();
+
+        match self.config.rules.duplicate_detection {
+            DuplicateDetection::AudioHash | DuplicateDetection::Both => {
+                for (path, hash) in hash_music_files(&paths, cache) {
+                    identities.entry(path).or_default().hash = Some(hash);
+                }
+            }
+            DuplicateDetection::Fingerprint => {
+                for (path, fingerprint) in fingerprint_music_files(&paths, cache) {
+                    identities.entry(path).or_default().fingerprint = Some(fingerprint);
+                }
+            }
+            DuplicateDetection::Tags => {}
+        }
+
+        identities
+    }
+
+    fn plan_file(
+        &mut self,
+        source_path: &Path,
+        metadata: &AudioMetadata,
+        identity: AudioIdentity,
+        plan: &mut Plan,
+    ) {
+        let config = self.config;
+        let index = self.planned + plan.operations.len();
+
+        let (relative_path, fallback) = match generate_target_path(source_path, metadata, config) {
+            Some(path) => (path, false),
+            None => {
+                if config.rules.handle_missing_metadata == MissingMetadataPolicy::Skip {
+                    plan.operations.push(PlannedOperation {
+                        source: source_path.to_path_buf(),
+                        action: PlannedAction::Skip,
+                        fallback: false,
+                        reason: None,
+                    });
+                    return;
+                } else {
+                    (generate_fallback_path(source_path, metadata, config), true)
+                }
+            }
+        };
+
+        let target_path = self.output_dir.join(&relative_path);
+        let metadata_key = create_metadata_key(source_path, metadata, config)
+            .unwrap_or_else(|| fallback_metadata_key(source_path));
+
+        let new_claim = Claim::new(target_path.clone(), Some(index), metadata);
+        let mut reason = None;
+
+        let action = match self.find_claim(&metadata_key, &identity) {
+            // A different recording already has this path, so number the new
+            // file instead of letting one replace the other.
+            None if self.is_claimed(&target_path) => {
+                let target = self.handle_duplicate_rename(new_claim, &metadata_key, identity);
+                reason = Some("another recording already has this path".to_string());
+                PlannedAction::Transfer { target }
+            }
+            None => {
+                let claim = self.claim(new_claim);
+                self.register(claim, Some(metadata_key), identity);
+                PlannedAction::Transfer {
+                    target: target_path,
+                }
+            }
+            Some(claim) => match config.rules.handle_duplicates {
+                DuplicatePolicy::Skip => PlannedAction::Duplicate {
+                    duplicate_of: self.claims[claim].path.clone(),
+                },
+                DuplicatePolicy::Rename => {
+                    let duplicate_of = self.claims[claim].path.clone();
+                    let target = self.handle_duplicate_rename(
+                        new_claim,
+                        &metadata_key,
+                        AudioIdentity::default(),
+                    );
+                    PlannedAction::Rename {
+                        target,
+                        duplicate_of,
+                    }
+                }
+                DuplicatePolicy::Overwrite => {
+                    let previous = std::mem::replace(&mut self.claims[claim], new_claim);
+                    self.by_target.insert(target_path.clone(), claim);
+                    self.register(claim, Some(metadata_key), identity);
+
+                    match previous.operation {
+                        Some(previous_index) if previous_index >= self.planned => {
+                            plan.operations[previous_index - self.planned].action =
+                                PlannedAction::Duplicate {
+                                    duplicate_of: target_path.clone(),
+                                };
+                            PlannedAction::Transfer {
+                                target: target_path,
+                            }
+                        }
+                        _ => PlannedAction::Overwrite {
+                            target: target_path,
+                            replaces: previous.path,
+                            set_aside: None,
+                        },
+                    }
+                }
+                DuplicatePolicy::Quarantine => {
+                    let duplicate_of = self.claims[claim].path.clone();
+                    self.set_aside(&target_path, duplicate_of)
+                }
+                DuplicatePolicy::KeepBest => {
+                    let (action, why) =
+                        self.keep_best(claim, new_claim, metadata_key, identity, plan);
+                    reason = why;
+                    action
+                }
+            },
+        };
+
+        plan.operations.push(PlannedOperation {
+            source: source_path.to_path_buf(),
+            action,
+            fallback,
+            reason,
+        });
+    }
+
+    /// Keeps whichever of the claimed file and the new candidate has the better
+    /// quality at the target path. The other one is set aside in the duplicates
+    /// folder when one is configured, and dropped otherwise.
+    fn keep_best(
+        &mut self,
+        claim: usize,
+        candidate: Claim,
+        metadata_key: MetadataKey,
+        identity: AudioIdentity,
+        plan: &mut Plan,
+    ) -> (PlannedAction, Option<String>) {
+        let (ordering, reason) = candidate.quality.compare(&self.claims[claim].quality);
+
+        if ordering != Ordering::Greater {
+            let duplicate_of = self.claims[claim].path.clone();
+            let action = self.set_aside(&candidate.path, duplicate_of);
+            return (action, Some(reason));
+        }
+
+        let target_path = candidate.path.clone();
+        let previous = std::mem::replace(&mut self.claims[claim], candidate);
+        self.by_target.insert(target_path.clone(), claim);
+        self.register(claim, Some(metadata_key), identity);
+
+        match previous.operation {
+            Some(previous_index) if previous_index >= self.planned => {
+                let previous_op = &plan.operations[previous_index - self.planned];
+                let previous_target = previous_op
+                    .action
+                    .target()
+                    .cloned()
+                    .unwrap_or(previous.path);
+                let action = match &previous_op.action {
+                    PlannedAction::Overwrite {
+                        replaces,
+                        set_aside,
+                        ..
+                    } => PlannedAction::Overwrite {
+                        target: target_path.clone(),
+                        replaces: replaces.clone(),
+                        set_aside: set_aside.clone(),
+                    },
+                    _ => PlannedAction::Transfer {
+                        target: target_path.clone(),
+                    },
+                };
+
+                let demoted = self.set_aside(&previous_target, target_path);
+                let previous_op = &mut plan.operations[previous_index - self.planned];
+                previous_op.action = demoted;
+                previous_op.reason = Some(reason);
+                (action, None)
+            }
+            _ => {
+                let set_aside = self
+                    .duplicates_dir()
+                    .map(|_| self.quarantine_path(&previous.path));
+                let action = PlannedAction::Overwrite {
+                    target: target_path,
+                    replaces: previous.path,
+                    set_aside,
+                };
+                (action, Some(reason))
+            }
+        }
+    }
+
+    fn set_aside(&mut self, target_path: &Path, duplicate_of: PathBuf) -> PlannedAction {
+        match self.duplicates_dir() {
+            Some(_) => PlannedAction::Quarantine {
+                target: self.quarantine_path(target_path),
+                duplicate_of,
+            },
+            None => PlannedAction::Duplicate { duplicate_of },
+        }
+    }
+
+    fn duplicates_dir(&self) -> Option<PathBuf> {
+        let rules = &self.config.rules;
+        let duplicates_dir = match (&rules.duplicates_dir, rules.handle_duplicates) {
+            (Some(duplicates_dir), _) => duplicates_dir.as_str(),
+            (None, DuplicatePolicy::Quarantine) => DEFAULT_QUARANTINE_DIR,
+            (None, _) => return None,
+        };
+        Some(self.output_dir.join(duplicates_dir))
+    }
+
+    /// Mirrors a path from the output directory into the duplicates folder,
+    /// numbering it when another duplicate already went to the same place.
+    fn quarantine_path(&mut self, path: &Path) -> PathBuf {
+        let duplicates_dir = self.duplicates_dir().unwrap_or_default();
+        let relative = path
+            .strip_prefix(self.output_dir)
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|_| PathBuf::from(path.file_name().unwrap_or_default()));
+        let base = duplicates_dir.join(relative);
+
+        let mut candidate = base.clone();
+        let mut counter = 1;
+        while self.quarantined.contains(&candidate) || candidate.exists() {
+            let stem = base.file_stem().unwrap_or_default().to_string_lossy();
+            let extension = base
+                .extension()
+                .map(|e| format!(".{}", e.to_string_lossy()))
+                .unwrap_or_default();
+            candidate = base.with_file_name(format!("{} ({}){}", stem, counter, extension));
+            counter += 1;
+        }
+
+        self.quarantined.insert(candidate.clone());
+        candidate
+    }
+
+    fn find_claim(&self, metadata_key: &MetadataKey, identity: &AudioIdentity) -> Option<usize> {
+        let by_tags = self.by_metadata.get(metadata_key).copied();
+        let by_audio_hash = identity
+            .hash
+            .as_ref()
+            .and_then(|hash| self.by_audio_hash.get(hash).copied());
+
+        match self.config.rules.duplicate_detection {
+            DuplicateDetection::AudioHash if identity.hash.is_some() => by_audio_hash,
+            DuplicateDetection::Both => by_audio_hash.or(by_tags),
+            DuplicateDetection::Fingerprint => match &identity.fingerprint {
+                Some(fingerprint) => self.find_similar(fingerprint),
+                None => by_tags,
+            },
+            _ => by_tags,
+        }
+    }
+
+    /// The most similar claim above the threshold. Only recordings of about
+    /// the same length are compared, but within that every one of them is.
+    fn find_similar(&self, fingerprint: &Fingerprint) -> Option<usize> {
+        let threshold = self.config.rules.fingerprint_threshold;
+
+        let bucket = fingerprint.duration_bucket();
+        (bucket - 1..=bucket + 1)
+            .filter_map(|bucket| self.fingerprints.get(&bucket))
+            .flatten()
+            .map(|(claim, other)| (*claim, fingerprint.similarity(other)))
+            .filter(|&(_, similarity)| similarity >= threshold)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(claim, _)| claim)
+    }
+
+    fn claim(&mut self, claim: Claim) -> usize {
+        self.by_target.insert(claim.path.clone(), self.claims.len());
+        self.claims.push(claim);
+        self.claims.len() - 1
+    }
+
+    /// Whether a claimed file still sits at `path`. Overwrites move claims to
+    /// new paths, so the entry is checked against the claim itself.
+    fn is_claimed(&self, path: &Path) -> bool {
+        self.by_target
+            .get(path)
+            .is_some_and(|&claim| self.claims[claim].path == path)
+    }
+
+    fn register(
+        &mut self,
+        claim: usize,
+        metadata_key: Option<MetadataKey>,
+        identity: AudioIdentity,
+    ) {
+        if let Some(metadata_key) = metadata_key {
+            self.by_metadata.insert(metadata_key, claim);
+        }
+        if let Some(hash) = identity.hash {
+            self.by_audio_hash.insert(hash, claim);
+        }
+        if let Some(fingerprint) = identity.fingerprint {
+            let bucket = fingerprint.duration_bucket();
+            if let Some(previous) = self.fingerprint_buckets.insert(claim, bucket)
+                && let Some(entries) = self.fingerprints.get_mut(&previous)
+            {
+                entries.retain(|(existing, _)| *existing != claim);
+            }
+            self.fingerprints
+                .entry(bucket)
+                .or_default()
+                .push((claim, fingerprint));
+        }
+    }
+
+    fn handle_duplicate_rename(
+        &mut self,
+        mut claim: Claim,
+        metadata_key: &MetadataKey,
+        identity: AudioIdentity,
+    ) -> PathBuf {
+        let target_path = claim.path.clone();
+        let mut counter = 1;
+        let stem = target_path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let extension = target_path
+            .extension()
+            .map(|e| format!(".{}", e.to_string_lossy()))
+            .unwrap_or_default();
+        let parent = target_path.parent().unwrap_or(Path::new(""));
+
+        loop {
+            let new_filename = format!("{} ({}){}", stem, counter, extension);
+            let new_path = parent.join(new_filename);
+
+            let mut new_metadata_key = metadata_key.clone();
+            new_metadata_key.copy = counter;
+
+            if !self.by_metadata.contains_key(&new_metadata_key)
+                && !self.by_target.contains_key(&new_path)
+                && !new_path.exists()
+            {
+                claim.path = new_path.clone();
+                let claim = self.claim(claim);
+                self.register(claim, Some(new_metadata_key), identity);
+                return new_path;
+            }
+            counter += 1;
+        }
+    }
+}
+
+pub fn plan_music_files(
+    music_files: &[(PathBuf, AudioMetadata)],
+    output_dir: &Path,
+    config: &Config,
+    cache: &mut ScanCache,
+) -> Plan {
+    // The scan reports existing files by absolute path, so targets have to be
+    // absolute too for the two to be compared.
+    let output_dir = std::path::absolute(output_dir).unwrap_or_else(|_| output_dir.to_path_buf());
+    Planner::new(&output_dir, config, cache).plan(music_files, cache)
 }
 
 pub fn organize_music_files(
     music_files: &[(PathBuf, AudioMetadata)],
-    output_dir: &PathBuf,
+    output_dir: &Path,
     config: &Config,
+    cache: &mut ScanCache,
 ) -> Result<OrganizeResult, Box<dyn std::error::Error>> {
     if music_files.is_empty() {
         return Ok(OrganizeResult {
@@ -37,12 +519,30 @@ pub fn organize_music_files(
             skipped: 0,
             failed: 0,
             duplicates: 0,
+            fallback: 0,
+            entries: Vec::new(),
         });
     }
 
+    let output_dir = std::path::absolute(output_dir)?;
+    let plan = plan_music_files(music_files, &output_dir, config, cache);
+    let journal = Journal::create(&output_dir)?;
+    let result = execute_plan(&plan, &journal)?;
+
+    if let Some(journal_path) = journal.finish()? {
+        println!("  Journal written to {}", journal_path.display());
+    }
+
+    Ok(result)
+}
+
+pub fn execute_plan(
+    plan: &Plan,
+    journal: &Journal,
+) -> Result<OrganizeResult, Box<dyn std::error::Error>> {
     let start_time = Instant::now();
 
-    let pb = Arc::new(ProgressBar::new(music_files.len() as u64));
+    let pb = Arc::new(ProgressBar::new(plan.operations.len() as u64));
     pb.set_style(
         ProgressStyle::default_bar()
             .template("  [{bar:40.cyan/blue}] {pos}/{len} [{elapsed_precise}] {msg}")?
@@ -53,43 +553,100 @@ pub fn organize_music_files(
     let skipped = Arc::new(Mutex::new(0));
     let failed = Arc::new(Mutex::new(0));
     let duplicates = Arc::new(Mutex::new(0));
+    let fallback = Arc::new(Mutex::new(0));
+    let manifest = plan.manifest.as_deref().map(Manifest::new);
 
-    let used_metadata: Arc<Mutex<HashMap<MetadataKey, PathBuf>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-
-    if let Ok(existing_files) = crate::scan::scan_for_music(output_dir) {
-        let mut metadata_map = used_metadata.lock().unwrap();
-        for (path, metadata) in existing_files {
-            if let Some(metadata_key) = create_metadata_key(&metadata) {
-                metadata_map.insert(metadata_key, path);
+    let entries: Vec<ReportEntry> = plan
+        .operations
+        .par_iter()
+        .map(|op| {
+            if let Some(filename) = op.source.file_name() {
+                pb.set_message(filename.to_string_lossy().to_string());
             }
-        }
-    }
 
-    music_files.par_iter().for_each(|(source_path, metadata)| {
-        if let Some(filename) = source_path.file_name() {
-            pb.set_message(filename.to_string_lossy().to_string());
-        }
+            if let Some(reason) = &op.reason {
+                pb.println(format!("  {}: {}", op.source.display(), reason));
+            }
 
-        match organize_single_file(source_path, metadata, output_dir, config, &used_metadata) {
-            Ok(result) => match result {
-                FileResult::Moved => {
-                    *moved.lock().unwrap() += 1;
-                }
-                FileResult::Skipped => {
-                    *skipped.lock().unwrap() += 1;
+            let mut entry = ReportEntry {
+                source: op.source.clone(),
+                target: op.action.target().cloned(),
+                outcome: Outcome::Copied,
+                duplicate_of: op.action.duplicate_of().cloned(),
+                set_aside: op.action.set_aside().cloned(),
+                reason: op.reason.clone(),
+                error: None,
+                metadata: None,
+                mismatched: false,
+            };
+            let mut note = |outcome: TransferOutcome| {
+                if let TransferOutcome::CopiedInstead(reason) = outcome {
+                    pb.println(format!("  {}: {}", op.source.display(), reason));
+                    entry.reason = Some(match entry.reason.take() {
+                        Some(existing) => format!("{}; {}", existing, reason),
+                        None => reason,
+                    });
                 }
-                FileResult::Duplicate => {
-                    *duplicates.lock().unwrap() += 1;
+            };
+
+            match execute_operation(op, plan.mode, journal) {
+                Ok(result) => match result {
+                    FileResult::Moved(outcome) => {
+                        note(outcome);
+                        entry.outcome = if op.fallback {
+                            *fallback.lock().unwrap() += 1;
+                            Outcome::Fallback
+                        } else {
+                            Outcome::Copied
+                        };
+                        if let Some(manifest) = &manifest
+                            && let Err(e) = manifest.record(op)
+                        {
+                            pb.println(format!(
+                                "  WARNING: Failed to update quarantine manifest: {}",
+                                e
+                            ));
+                        }
+                        *moved.lock().unwrap() += 1;
+                    }
+                    FileResult::Quarantined(outcome) => {
+                        note(outcome);
+                        entry.outcome = Outcome::Duplicate;
+                        if let Some(manifest) = &manifest
+                            && let Err(e) = manifest.record(op)
+                        {
+                            pb.println(format!(
+                                "  WARNING: Failed to update quarantine manifest: {}",
+                                e
+                            ));
+                        }
+                        *duplicates.lock().unwrap() += 1;
+                    }
+                    FileResult::Skipped => {
+                        entry.outcome = Outcome::Skipped;
+                        *skipped.lock().unwrap() += 1;
+                    }
+                    FileResult::Duplicate => {
+                        entry.outcome = Outcome::Duplicate;
+                        *duplicates.lock().unwrap() += 1;
+                    }
+                },
+                Err(e) => {
+                    pb.println(format!(
+                        "  Failed to organize {}: {}",
+                        op.source.display(),
+                        e
+                    ));
+                    entry.outcome = Outcome::Failed;
+                    entry.error = Some(e.to_string());
+                    *failed.lock().unwrap() += 1;
                 }
-            },
-            Err(_) => {
-                *failed.lock().unwrap() += 1;
             }
-        }
 
-        pb.inc(1);
-    });
+            pb.inc(1);
+            entry
+        })
+        .collect();
 
     pb.finish_and_clear();
 
@@ -99,11 +656,14 @@ pub fn organize_music_files(
         skipped: *skipped.lock().unwrap(),
         failed: *failed.lock().unwrap(),
         duplicates: *duplicates.lock().unwrap(),
+        fallback: *fallback.lock().unwrap(),
+        entries,
     };
 
     println!(
-        "  {} files copied in {:.2}s",
+        "  {} files {} in {:.2}s",
         result.moved,
+        plan.mode.past_tense(),
         duration.as_secs_f64()
     );
     if result.skipped > 0 {
@@ -120,96 +680,66 @@ pub fn organize_music_files(
 }
 
 #[derive(Debug)]
-enum FileResult {
-    Moved,
+pub enum FileResult {
+    Moved(TransferOutcome),
+    Quarantined(TransferOutcome),
     Skipped,
     Duplicate,
 }
 
-fn organize_single_file(
-    source_path: &PathBuf,
-    metadata: &AudioMetadata,
-    output_dir: &PathBuf,
-    config: &Config,
-    used_metadata: &Arc<Mutex<HashMap<MetadataKey, PathBuf>>>,
+pub fn execute_operation(
+    op: &PlannedOperation,
+    mode: TransferMode,
+    journal: &Journal,
 ) -> Result<FileResult, Box<dyn std::error::Error>> {
-    let relative_path = match generate_target_path(source_path, metadata, config) {
-        Some(path) => path,
-        None => {
-            if config.rules.handle_missing_metadata == "skip" {
-                return Ok(FileResult::Skipped);
-            } else {
-                generate_fallback_path(source_path, config)
+    let target_path = match &op.action {
+        PlannedAction::Skip => return Ok(FileResult::Skipped),
+        PlannedAction::Duplicate { .. } => return Ok(FileResult::Duplicate),
+        PlannedAction::Overwrite {
+            target,
+            replaces,
+            set_aside,
+        } => {
+            if let Some(set_aside) = set_aside {
+                if let Some(parent) = set_aside.parent() {
+                    journal.create_dir_all(parent)?;
+                }
+                transfer_file(replaces, set_aside, TransferMode::Move)?;
+                journal.record_transfer(replaces, set_aside, TransferMode::Move)?;
+            } else if replaces != target && fs::remove_file(replaces).is_ok() {
+                journal.record_removal(replaces)?;
             }
+            target
         }
+        PlannedAction::Transfer { target }
+        | PlannedAction::Rename { target, .. }
+        | PlannedAction::Quarantine { target, .. } => target,
     };
 
-    let target_path = output_dir.join(&relative_path);
-
-    let final_target_path = {
-        let metadata_key = create_metadata_key(metadata);
-        let mut metadata_map = used_metadata.lock().unwrap();
-
-        if let Some(metadata_key) = metadata_key {
-            if metadata_map.contains_key(&metadata_key) {
-                match config.rules.handle_duplicates.as_str() {
-                    "skip" => {
-                        return Ok(FileResult::Duplicate);
-                    }
-                    "rename" => {
-                        handle_duplicate_rename(&target_path, &metadata_key, &mut metadata_map)
-                    }
-                    "overwrite" => {
-                        if let Some(old_path) = metadata_map.get(&metadata_key) {
-                            let _ = fs::remove_file(old_path);
-                        }
-                        metadata_map.insert(metadata_key, source_path.clone());
-                        target_path
-                    }
-                    _ => target_path,
-                }
-            } else {
-                metadata_map.insert(metadata_key, source_path.clone());
-                target_path
-            }
-        } else {
-            let fallback_key = MetadataKey {
-                artist: "Unknown".to_string(),
-                album: "Unknown".to_string(),
-                title: source_path
-                    .file_stem()
-                    .unwrap_or_default()
-                    .to_string_lossy()
-                    .to_string(),
-                track: None,
-            };
+    if let Some(parent) = target_path.parent() {
+        journal.create_dir_all(parent)?;
+    }
 
-            if metadata_map.contains_key(&fallback_key) {
-                match config.rules.handle_duplicates.as_str() {
-                    "skip" => return Ok(FileResult::Duplicate),
-                    "rename" => {
-                        handle_duplicate_rename(&target_path, &fallback_key, &mut metadata_map)
-                    }
-                    _ => target_path,
-                }
-            } else {
-                metadata_map.insert(fallback_key, source_path.clone());
-                target_path
-            }
+    // Only an overwrite may replace a file. Anything else in the way appeared
+    // after planning, and replacing it would lose it for good.
+    if fs::symlink_metadata(target_path).is_ok() {
+        if !matches!(op.action, PlannedAction::Overwrite { .. }) {
+            return Err(format!("{} already exists", target_path.display()).into());
         }
-    };
-
-    if let Some(parent) = final_target_path.parent() {
-        fs::create_dir_all(parent)?;
+        journal.record_removal(target_path)?;
     }
 
-    fs::copy(source_path, &final_target_path)?;
+    let outcome = transfer_file(&op.source, target_path, mode)?;
+    journal.record_transfer(&op.source, target_path, mode)?;
 
-    Ok(FileResult::Moved)
+    if let PlannedAction::Quarantine { .. } = op.action {
+        return Ok(FileResult::Quarantined(outcome));
+    }
+    Ok(FileResult::Moved(outcome))
 }
 
 fn generate_target_path(
-    source_path: &PathBuf,
+    source_path: &Path,
     metadata: &AudioMetadata,
     config: &Config,
 ) -> Option<PathBuf> {
@@ -223,21 +753,40 @@ fn generate_target_path(
         &config.organization.structure
     };
 
-    let path_str = replace_placeholders(structure, source_path, metadata, config)?;
+    render_template(structure, source_path, metadata, config)
+}
+
+/// Renders one template into a relative target path, or `None` when the
+/// metadata lacks a placeholder the template requires.
+pub fn render_template(
+    template: &str,
+    source_path: &Path,
+    metadata: &AudioMetadata,
+    config: &Config,
+) -> Option<PathBuf> {
+    let path_str = replace_placeholders(template, source_path, metadata, config)?;
     let sanitized_path = sanitize_path(&path_str, config);
     Some(PathBuf::from(sanitized_path))
 }
 
-fn generate_fallback_path(source_path: &PathBuf, config: &Config) -> PathBuf {
-    let filename = source_path
-        .file_name()
-        .unwrap_or_default()
-        .to_string_lossy();
-
-    let fallback_str = config
-        .organization
-        .fallback_structure
-        .replace("{filename}", &filename);
+fn generate_fallback_path(
+    source_path: &Path,
+    metadata: &AudioMetadata,
+    config: &Config,
+) -> PathBuf {
+    let fallback_str = replace_placeholders(
+        &config.organization.fallback_structure,
+        source_path,
+        metadata,
+        config,
+    )
+    .unwrap_or_else(|| {
+        source_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string()
+    });
 
     let sanitized_path = sanitize_path(&fallback_str, config);
     PathBuf::from(sanitized_path)
@@ -245,89 +794,112 @@ fn generate_fallback_path(source_path: &PathBuf, config: &Config) -> PathBuf {
 
 fn replace_placeholders(
     template: &str,
-    source_path: &PathBuf,
+    source_path: &Path,
     metadata: &AudioMetadata,
     config: &Config,
 ) -> Option<String> {
-    let mut result = template.to_string();
+    let template = Template::parse(template).ok()?;
+    let mut result = template.render(
+        |field| placeholder_value(field, source_path, metadata),
+        |value| sanitize_metadata_value(value, config),
+    )?;
 
-    if template.contains("{artist}") {
-        if is_compilation(metadata) {
-            if let Some(artist) = &metadata.artist {
-                result = result.replace("{artist}", &sanitize_metadata_value(artist, config));
-            } else {
-                return None;
-            }
-        } else if let Some(album_artist) = &metadata.album_artist {
-            result = result.replace("{artist}", &sanitize_metadata_value(album_artist, config));
-        } else if let Some(artist) = &metadata.artist {
-            result = result.replace("{artist}", &sanitize_metadata_value(artist, config));
-        } else {
-            return None;
-        }
+    if let Some(extension) = target_extension(source_path, metadata, config)
+        && !result.ends_with(&format!(".{}", extension))
+    {
+        result = format!("{}.{}", result, extension);
     }
 
-    if let Some(title) = &metadata.title {
-        result = result.replace("{title}", &sanitize_metadata_value(title, config));
-    } else if template.contains("{title}") {
-        return None;
-    }
+    Some(result)
+}
 
-    if let Some(album) = &metadata.album {
-        result = result.replace("{album}", &sanitize_metadata_value(album, config));
-    } else if template.contains("{album}") {
-        return None;
+/// The source's own extension, or with `fix_extensions` the one its detected
+/// contents call for when the two disagree.
+fn target_extension(
+    source_path: &Path,
+    metadata: &AudioMetadata,
+    config: &Config,
+) -> Option<String> {
+    if config.formatting.fix_extensions
+        && let Some(format) = &metadata.format
+        && is_mismatched(source_path, format)
+    {
+        return Some(format.clone());
     }
 
-    if let Some(year) = metadata.year {
-        result = result.replace("{year}", &year.to_string());
-    } else if template.contains("{year}") {
-        return None;
-    }
+    source_path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_string())
+}
 
-    if template.contains("{track") {
-        if let Some(track) = metadata.track {
-            if let Some(start) = template.find("{track") {
-                if let Some(end) = template[start..].find('}') {
-                    let full_placeholder = &template[start..start + end + 1];
-                    if full_placeholder.contains(':') {
-                        let format_part = &full_placeholder[7..full_placeholder.len() - 1];
-                        if format_part == "02" {
-                            result = result.replace(full_placeholder, &format!("{:02}", track));
-                        } else {
-                            result = result.replace(full_placeholder, &track.to_string());
-                        }
-                    } else {
-                        result = result.replace("{track}", &track.to_string());
-                    }
-                }
-            }
-        } else if template.contains("{track") {
-            return None;
+fn placeholder_value(field: &str, source_path: &Path, metadata: &AudioMetadata) -> Option<Value> {
+    match field {
+        "artist" => {
+            let artist = if is_compilation(metadata) {
+                metadata.artist.as_ref()
+            } else {
+                metadata.album_artist.as_ref().or(metadata.artist.as_ref())
+            };
+            artist.cloned().map(Value::Text)
         }
+        "albumartist" => metadata.album_artist.clone().map(Value::Text),
+        "title" => metadata.title.clone().map(Value::Text),
+        "album" => metadata.album.clone().map(Value::Text),
+        "year" => metadata.year.map(|year| Value::Number(year as i64)),
+        "track" => metadata.track.map(|track| Value::Number(track as i64)),
+        "tracktotal" => metadata
+            .track_total
+            .map(|total| Value::Number(total as i64)),
+        "disc" => metadata.disc.map(|disc| Value::Number(disc as i64)),
+        "disctotal" => metadata.disc_total.map(|total| Value::Number(total as i64)),
+        "genre" => metadata.genre.clone().map(Value::Text),
+        "filename" => Some(Value::Text(
+            source_path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+        )),
+        "composer" => metadata.composer.clone().map(Value::Text),
+        "conductor" => metadata.conductor.clone().map(Value::Text),
+        "performer" => metadata.performer.clone().map(Value::Text),
+        "albumsort" => metadata.album_sort.clone().map(Value::Text),
+        "artistsort" => metadata.artist_sort.clone().map(Value::Text),
+        "originaldate" => metadata.original_date.clone().map(Value::Text),
+        "originalyear" => metadata
+            .original_year()
+            .map(|year| Value::Number(year as i64)),
+        "label" => metadata.label.clone().map(Value::Text),
+        "catalognumber" => metadata.catalog_number.clone().map(Value::Text),
+        "isrc" => metadata.isrc.clone().map(Value::Text),
+        "bpm" => metadata.bpm.map(|bpm| Value::Number(bpm as i64)),
+        "key" => metadata.key.clone().map(Value::Text),
+        "comment" => metadata.comment.clone().map(Value::Text),
+        "mbrecordingid" => metadata.musicbrainz_recording_id.clone().map(Value::Text),
+        "mbreleaseid" => metadata.musicbrainz_release_id.clone().map(Value::Text),
+        "mbartistid" => metadata.musicbrainz_artist_id.clone().map(Value::Text),
+        _ => property_value(field, metadata.properties.as_ref()?),
     }
+}
 
-    if let Some(genre) = &metadata.genre {
-        result = result.replace("{genre}", &sanitize_metadata_value(genre, config));
-    } else if template.contains("{genre}") {
-        return None;
-    }
+fn property_value(field: &str, properties: &AudioProperties) -> Option<Value> {
+    let number = |value: Option<u32>| value.map(|value| Value::Number(value as i64));
 
-    if template.contains("{filename}") {
-        let filename = source_path
-            .file_name()
-            .unwrap_or_default()
-            .to_string_lossy();
-        result = result.replace("{filename}", &filename);
+    match field {
+        "codec" => Some(Value::Text(properties.codec.clone())),
+        "container" => Some(Value::Text(properties.container.clone())),
+        "bitrate" => number(properties.bitrate),
+        "bitratemode" => properties
+            .vbr
+            .map(|vbr| Value::Text(if vbr { "VBR" } else { "CBR" }.to_string())),
+        "samplerate" => number(properties.sample_rate),
+        "bitdepth" => number(properties.bit_depth),
+        "channels" => number(properties.channels),
+        "duration" => properties
+            .duration
+            .map(|duration| Value::Number(duration.round() as i64)),
+        _ => None,
     }
-
-    if let Some(extension) = source_path.extension() {
-        if !result.ends_with(&format!(".{}", extension.to_string_lossy())) {
-            result = format!("{}.{}", result, extension.to_string_lossy());
-        }
-    }
-
-    Some(result)
 }
 
 fn sanitize_path(path: &str, config: &Config) -> String {
@@ -370,38 +942,45 @@ fn sanitize_path(path: &str, config: &Config) -> String {
     sanitized_parts.join("/")
 }
 
-fn handle_duplicate_rename(
-    target_path: &PathBuf,
-    metadata_key: &MetadataKey,
-    used_metadata: &mut HashMap<MetadataKey, PathBuf>,
-) -> PathBuf {
-    let mut counter = 1;
-    let stem = target_path
+fn fallback_metadata_key(source_path: &Path) -> MetadataKey {
+    let stem = source_path
         .file_stem()
         .unwrap_or_default()
         .to_string_lossy();
-    let extension = target_path
-        .extension()
-        .map(|e| format!(".{}", e.to_string_lossy()))
-        .unwrap_or_default();
-    let parent = target_path.parent().unwrap_or(Path::new(""));
 
-    loop {
-        let new_filename = format!("{} ({}){}", stem, counter, extension);
-        let new_path = parent.join(new_filename);
-
-        let mut new_metadata_key = metadata_key.clone();
-        new_metadata_key.title = format!("{} ({})", metadata_key.title, counter);
-
-        if !used_metadata.contains_key(&new_metadata_key) {
-            used_metadata.insert(new_metadata_key, new_path.clone());
-            return new_path;
-        }
-        counter += 1;
+    MetadataKey {
+        values: vec![
+            Some("Unknown".to_string()),
+            Some("Unknown".to_string()),
+            Some(stem.to_string()),
+            None,
+            None,
+        ],
+        copy: 0,
     }
 }
 
-fn create_metadata_key(metadata: &AudioMetadata) -> Option<MetadataKey> {
+/// Builds the duplicate key from `rules.duplicate_key` when it is set, in
+/// which case every listed field must be present. Otherwise the key is the
+/// artist, album and title, plus the track and disc numbers when tagged.
+fn create_metadata_key(
+    source_path: &Path,
+    metadata: &AudioMetadata,
+    config: &Config,
+) -> Option<MetadataKey> {
+    if let Some(fields) = &config.rules.duplicate_key {
+        let values = fields
+            .iter()
+            .map(
+                |field| match placeholder_value(field, source_path, metadata)? {
+                    Value::Text(text) => Some(Some(text)),
+                    Value::Number(number) => Some(Some(number.to_string())),
+                },
+            )
+            .collect::<Option<Vec<_>>>()?;
+        return Some(MetadataKey { values, copy: 0 });
+    }
+
     let artist = if is_compilation(metadata) {
         metadata.artist.as_ref()
     } else {
@@ -413,10 +992,14 @@ fn create_metadata_key(metadata: &AudioMetadata) -> Option<MetadataKey> {
     let title = metadata.title.as_ref()?;
 
     Some(MetadataKey {
-        artist: artist.clone(),
-        album: album.clone(),
-        title: title.clone(),
-        track: metadata.track,
+        values: vec![
+            Some(artist.clone()),
+            Some(album.clone()),
+            Some(title.clone()),
+            metadata.track.map(|track| track.to_string()),
+            metadata.disc.map(|disc| disc.to_string()),
+        ],
+        copy: 0,
     })
 }
 
@@ -435,3 +1018,129 @@ fn is_compilation(metadata: &AudioMetadata) -> bool {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(title: &str) -> AudioMetadata {
+        AudioMetadata {
+            title: Some(title.to_string()),
+            artist: Some("Artist".to_string()),
+            album: Some("Album".to_string()),
+            year: Some(2001),
+            track: Some(1),
+            ..Default::default()
+        }
+    }
+
+    fn with_bitrate(mut metadata: AudioMetadata, bitrate: u32) -> AudioMetadata {
+        metadata.properties = Some(AudioProperties {
+            codec: "mp3".to_string(),
+            container: "mp3".to_string(),
+            lossless: false,
+            bitrate: Some(bitrate),
+            vbr: None,
+            sample_rate: Some(44100),
+            bit_depth: None,
+            channels: Some(2),
+            duration: Some(180.0),
+        });
+        metadata
+    }
+
+    /// Plans `files` into an output directory that does not exist, so
+    /// nothing is claimed up front.
+    fn plan(config: &Config, files: &[(&str, AudioMetadata)]) -> (PathBuf, Vec<PlannedOperation>) {
+        let output_dir = std::env::temp_dir()
+            .join(format!("ufrume-planner-{}", std::process::id()))
+            .join(files.len().to_string());
+        let files: Vec<(PathBuf, AudioMetadata)> = files
+            .iter()
+            .map(|(name, metadata)| (PathBuf::from("/in").join(name), metadata.clone()))
+            .collect();
+        let plan = plan_music_files(&files, &output_dir, config, &mut ScanCache::default());
+        (output_dir, plan.operations)
+    }
+
+    fn target(op: &PlannedOperation) -> &Path {
+        op.action.target().unwrap()
+    }
+
+    #[test]
+    fn skips_duplicates_by_default() {
+        let (output_dir, ops) = plan(
+            &Config::default(),
+            &[("a.mp3", song("Song")), ("b.mp3", song("Song"))],
+        );
+        let expected = output_dir.join("Artist/2001 - Album/01 - Song.mp3");
+        assert_eq!(target(&ops[0]), expected);
+        assert!(matches!(
+            &ops[1].action,
+            PlannedAction::Duplicate { duplicate_of } if *duplicate_of == expected
+        ));
+    }
+
+    #[test]
+    fn numbers_renamed_duplicates() {
+        let mut config = Config::default();
+        config.rules.handle_duplicates = DuplicatePolicy::Rename;
+        let files = [
+            ("a.mp3", song("Song")),
+            ("b.mp3", song("Song")),
+            ("c.mp3", song("Song")),
+        ];
+        let (output_dir, ops) = plan(&config, &files);
+        let album = output_dir.join("Artist/2001 - Album");
+        assert_eq!(target(&ops[1]), album.join("01 - Song (1).mp3"));
+        assert_eq!(target(&ops[2]), album.join("01 - Song (2).mp3"));
+    }
+
+    #[test]
+    fn numbers_different_recordings_with_the_same_target() {
+        let mut config = Config::default();
+        config.rules.duplicate_key = Some(vec!["isrc".to_string()]);
+        let mut first = song("Song");
+        first.isrc = Some("A".to_string());
+        let mut second = song("Song");
+        second.isrc = Some("B".to_string());
+
+        let (output_dir, ops) = plan(&config, &[("a.mp3", first), ("b.mp3", second)]);
+        assert!(matches!(ops[1].action, PlannedAction::Transfer { .. }));
+        assert_eq!(
+            target(&ops[1]),
+            output_dir.join("Artist/2001 - Album/01 - Song (1).mp3")
+        );
+    }
+
+    #[test]
+    fn quarantines_into_the_duplicates_folder() {
+        let mut config = Config::default();
+        config.rules.handle_duplicates = DuplicatePolicy::Quarantine;
+        config.rules.duplicates_dir = Some("dups".to_string());
+        let (output_dir, ops) = plan(&config, &[("a.mp3", song("Song")), ("b.mp3", song("Song"))]);
+        assert!(matches!(ops[1].action, PlannedAction::Quarantine { .. }));
+        assert_eq!(
+            target(&ops[1]),
+            output_dir.join("dups/Artist/2001 - Album/01 - Song.mp3")
+        );
+    }
+
+    #[test]
+    fn keeps_the_better_copy() {
+        let mut config = Config::default();
+        config.rules.handle_duplicates = DuplicatePolicy::KeepBest;
+        let files = [
+            ("low.mp3", with_bitrate(song("Song"), 128)),
+            ("high.mp3", with_bitrate(song("Song"), 320)),
+        ];
+        let (output_dir, ops) = plan(&config, &files);
+        let expected = output_dir.join("Artist/2001 - Album/01 - Song.mp3");
+        assert!(matches!(
+            &ops[0].action,
+            PlannedAction::Duplicate { duplicate_of } if *duplicate_of == expected
+        ));
+        assert_eq!(target(&ops[1]), expected);
+        assert_eq!(ops[0].reason.as_deref(), Some("320 kbps over 128 kbps"));
+    }
+}
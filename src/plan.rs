@@ -0,0 +1,184 @@
+use crate::config::TransferMode;
+
+use std::{fmt, path::PathBuf};
+
+#[derive(Debug)]
+pub struct Plan {
+    pub mode: TransferMode,
+    pub operations: Vec<PlannedOperation>,
+    pub manifest: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct PlannedOperation {
+    pub source: PathBuf,
+    pub action: PlannedAction,
+    pub fallback: bool,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum PlannedAction {
+    Transfer {
+        target: PathBuf,
+    },
+    Rename {
+        target: PathBuf,
+        duplicate_of: PathBuf,
+    },
+    Overwrite {
+        target: PathBuf,
+        replaces: PathBuf,
+        set_aside: Option<PathBuf>,
+    },
+    Quarantine {
+        target: PathBuf,
+        duplicate_of: PathBuf,
+    },
+    Skip,
+    Duplicate {
+        duplicate_of: PathBuf,
+    },
+}
+
+impl PlannedAction {
+    pub fn target(&self) -> Option<&PathBuf> {
+        match self {
+            PlannedAction::Transfer { target }
+            | PlannedAction::Rename { target, .. }
+            | PlannedAction::Overwrite { target, .. }
+            | PlannedAction::Quarantine { target, .. } => Some(target),
+            PlannedAction::Skip | PlannedAction::Duplicate { .. } => None,
+        }
+    }
+
+    pub fn duplicate_of(&self) -> Option<&PathBuf> {
+        match self {
+            PlannedAction::Rename { duplicate_of, .. }
+            | PlannedAction::Quarantine { duplicate_of, .. }
+            | PlannedAction::Duplicate { duplicate_of } => Some(duplicate_of),
+            PlannedAction::Transfer { .. }
+            | PlannedAction::Overwrite { .. }
+            | PlannedAction::Skip => None,
+        }
+    }
+
+    /// Where the file replaced by an overwrite is moved to, if anywhere.
+    pub fn set_aside(&self) -> Option<&PathBuf> {
+        match self {
+            PlannedAction::Overwrite { set_aside, .. } => set_aside.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+impl Plan {
+    pub fn transfers(&self) -> usize {
+        self.operations
+            .iter()
+            .filter(|op| {
+                op.action.target().is_some()
+                    && !matches!(op.action, PlannedAction::Quarantine { .. })
+            })
+            .count()
+    }
+
+    pub fn count(&self, matches: impl Fn(&PlannedAction) -> bool) -> usize {
+        self.operations
+            .iter()
+            .filter(|op| matches(&op.action))
+            .count()
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = self.mode.to_string().to_lowercase();
+
+        for op in &self.operations {
+            let fallback = if op.fallback { " [fallback]" } else { "" };
+            let reason = op
+                .reason
+                .as_ref()
+                .map(|reason| format!(": {}", reason))
+                .unwrap_or_default();
+            match &op.action {
+                PlannedAction::Transfer { target } => writeln!(
+                    f,
+                    "{:<10} {} -> {}{}{}",
+                    mode,
+                    op.source.display(),
+                    target.display(),
+                    fallback,
+                    op.reason
+                        .as_ref()
+                        .map(|reason| format!(" ({})", reason))
+                        .unwrap_or_default()
+                )?,
+                PlannedAction::Rename {
+                    target,
+                    duplicate_of,
+                } => writeln!(
+                    f,
+                    "{:<10} {} -> {}{} (duplicate of {}{})",
+                    "rename",
+                    op.source.display(),
+                    target.display(),
+                    fallback,
+                    duplicate_of.display(),
+                    reason
+                )?,
+                PlannedAction::Overwrite {
+                    target,
+                    replaces,
+                    set_aside,
+                } => {
+                    let set_aside = set_aside
+                        .as_ref()
+                        .map(|path| format!(", set aside to {}", path.display()))
+                        .unwrap_or_default();
+                    writeln!(
+                        f,
+                        "{:<10} {} -> {}{} (replaces {}{}{})",
+                        "overwrite",
+                        op.source.display(),
+                        target.display(),
+                        fallback,
+                        replaces.display(),
+                        set_aside,
+                        reason
+                    )?
+                }
+                PlannedAction::Quarantine {
+                    target,
+                    duplicate_of,
+                } => writeln!(
+                    f,
+                    "{:<10} {} -> {}{} (duplicate of {}{})",
+                    "quarantine",
+                    op.source.display(),
+                    target.display(),
+                    fallback,
+                    duplicate_of.display(),
+                    reason
+                )?,
+                PlannedAction::Skip => writeln!(
+                    f,
+                    "{:<10} {} (missing metadata)",
+                    "skip",
+                    op.source.display()
+                )?,
+                PlannedAction::Duplicate { duplicate_of } => writeln!(
+                    f,
+                    "{:<10} {} (duplicate of {}{})",
+                    "duplicate",
+                    op.source.display(),
+                    duplicate_of.display(),
+                    reason
+                )?,
+            }
+        }
+
+        Ok(())
+    }
+}
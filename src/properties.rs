@@ -0,0 +1,288 @@
+use crate::{audiohash::audio_payload_len, scan::AudioMetadata};
+
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, fs::File, path::Path};
+use symphonia::core::{
+    codecs::{CODEC_TYPE_NULL, CODEC_TYPE_OPUS},
+    errors::Error as SymphoniaError,
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+/// How many packets to look at when telling constant from variable bitrate.
+const BITRATE_SAMPLE_PACKETS: usize = 64;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AudioProperties {
+    pub codec: String,
+    pub container: String,
+    pub lossless: bool,
+    pub bitrate: Option<u32>,
+    pub vbr: Option<bool>,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u32>,
+    pub channels: Option<u32>,
+    pub duration: Option<f64>,
+}
+
+/// Reads the stream properties of a file whose type is `format`, the
+/// detected type or else the file's extension.
+pub fn read_properties(
+    path: &Path,
+    format: &str,
+) -> Result<AudioProperties, Box<dyn std::error::Error + Send + Sync>> {
+    let file = File::open(path)?;
+    let source = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(format);
+    let container = container_name(format);
+
+    let probed = match symphonia::default::get_probe().format(
+        &hint,
+        source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    ) {
+        Ok(probed) => probed,
+        Err(e) => return properties_from_format(format).ok_or_else(|| e.into()),
+    };
+    let mut format = probed.format;
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or("no audio track found")?;
+    let params = track.codec_params.clone();
+    let track_id = track.id;
+
+    let codec = symphonia::default::get_codecs()
+        .get_codec(params.codec)
+        .map(|descriptor| descriptor.short_name.to_string())
+        .unwrap_or_else(|| {
+            // Symphonia demuxes Opus but has no decoder to describe it.
+            let name = if params.codec == CODEC_TYPE_OPUS {
+                "opus"
+            } else {
+                "unknown"
+            };
+            name.to_string()
+        });
+    let lossless = is_lossless(&codec);
+    // MPEG audio frames only differ in size (beyond a padding byte) when the
+    // bitrate varies. Other lossy codecs vary their packet sizes either way.
+    let is_mpeg = matches!(codec.as_str(), "mp1" | "mp2" | "mp3");
+
+    let mut frames = params.n_frames;
+    let mut packet_sizes = Vec::new();
+    if frames.is_none() || is_mpeg {
+        // Some streams (MP3 without a Xing header, for example) do not state
+        // their length, so add up the packet durations instead.
+        let mut counted = 0;
+        loop {
+            if frames.is_some() && packet_sizes.len() >= BITRATE_SAMPLE_PACKETS {
+                break;
+            }
+            match format.next_packet() {
+                Ok(packet) if packet.track_id() == track_id => {
+                    counted += packet.dur;
+                    if packet_sizes.len() < BITRATE_SAMPLE_PACKETS {
+                        packet_sizes.push(packet.buf().len());
+                    }
+                }
+                Ok(_) => {}
+                Err(SymphoniaError::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if frames.is_none() {
+            frames = Some(counted).filter(|&frames| frames > 0);
+        }
+    }
+
+    let vbr = match (packet_sizes.iter().min(), packet_sizes.iter().max()) {
+        (Some(min), Some(max)) if is_mpeg => Some(max - min > 1),
+        _ => None,
+    };
+
+    let duration = match (frames, params.time_base, params.sample_rate) {
+        (Some(frames), Some(time_base), _) => {
+            let time = time_base.calc_time(frames);
+            Some(time.seconds as f64 + time.frac)
+        }
+        (Some(frames), None, Some(rate)) => Some(frames as f64 / rate as f64),
+        _ => None,
+    };
+
+    // Symphonia does not report bitrates, so work it out from the size of the
+    // audio data alone. Tags and cover art would count towards the file size.
+    let bitrate = match (duration, audio_payload_len(path)) {
+        (Some(duration), Ok(payload)) if duration > 0.0 && payload > 0 => {
+            Some((payload as f64 * 8.0 / duration / 1000.0).round() as u32)
+        }
+        _ => None,
+    };
+
+    Ok(AudioProperties {
+        codec,
+        container,
+        lossless,
+        bitrate,
+        vbr,
+        sample_rate: params.sample_rate,
+        bit_depth: params.bits_per_sample.or(params.bits_per_coded_sample),
+        channels: params.channels.map(|channels| channels.count() as u32),
+        duration,
+    })
+}
+
+fn container_name(format: &str) -> String {
+    match format {
+        "m4a" | "m4b" | "m4p" | "mp4" => "mp4".to_string(),
+        "oga" | "opus" => "ogg".to_string(),
+        "aac" => "adts".to_string(),
+        "aif" | "aifc" => "aiff".to_string(),
+        "wma" => "asf".to_string(),
+        _ => format.to_string(),
+    }
+}
+
+/// Symphonia cannot open these containers at all, so the codec the file type
+/// implies is all there is to go on.
+fn properties_from_format(format: &str) -> Option<AudioProperties> {
+    let container = container_name(format);
+    let codec = match container.as_str() {
+        "ape" => "ape",
+        "wv" => "wavpack",
+        "mpc" => "musepack",
+        "dsf" | "dff" => "dsd",
+        _ => return None,
+    };
+
+    Some(AudioProperties {
+        codec: codec.to_string(),
+        container,
+        lossless: is_lossless(codec),
+        bitrate: None,
+        vbr: None,
+        sample_rate: None,
+        bit_depth: None,
+        channels: None,
+        duration: None,
+    })
+}
+
+fn is_lossless(codec: &str) -> bool {
+    matches!(codec, "flac" | "alac" | "wavpack" | "ape" | "tta" | "dsd") || codec.starts_with("pcm")
+}
+
+/// What the `keep_best` duplicate policy knows about one candidate file.
+#[derive(Debug, Clone)]
+pub struct Quality {
+    pub properties: Option<AudioProperties>,
+    pub tag_count: usize,
+}
+
+impl Quality {
+    pub fn of(metadata: &AudioMetadata) -> Quality {
+        Quality {
+            properties: metadata.properties.clone(),
+            tag_count: tag_count(metadata),
+        }
+    }
+
+    /// Compares two candidates by codec, bitrate, sample rate, bit depth and
+    /// tag completeness, in that order. Bitrate only decides between two lossy
+    /// files, since a lossless bitrate says nothing about quality. Returns the
+    /// deciding criterion phrased as "winner over loser".
+    pub fn compare(&self, other: &Quality) -> (Ordering, String) {
+        if let (Some(a), Some(b)) = (&self.properties, &other.properties) {
+            let codec = |p: &AudioProperties| {
+                let kind = if p.lossless { "lossless" } else { "lossy" };
+                format!("{} ({})", p.codec, kind)
+            };
+
+            let checks = [
+                (a.lossless.cmp(&b.lossless), codec(a), codec(b)),
+                (
+                    if a.lossless || b.lossless {
+                        Ordering::Equal
+                    } else {
+                        a.bitrate.cmp(&b.bitrate)
+                    },
+                    format!("{} kbps", a.bitrate.unwrap_or_default()),
+                    format!("{} kbps", b.bitrate.unwrap_or_default()),
+                ),
+                (
+                    a.sample_rate.cmp(&b.sample_rate),
+                    format!("{} Hz", a.sample_rate.unwrap_or_default()),
+                    format!("{} Hz", b.sample_rate.unwrap_or_default()),
+                ),
+                (
+                    a.bit_depth.cmp(&b.bit_depth),
+                    format!("{}-bit", a.bit_depth.unwrap_or_default()),
+                    format!("{}-bit", b.bit_depth.unwrap_or_default()),
+                ),
+            ];
+
+            for (ordering, ours, theirs) in checks {
+                match ordering {
+                    Ordering::Greater => return (ordering, format!("{} over {}", ours, theirs)),
+                    Ordering::Less => return (ordering, format!("{} over {}", theirs, ours)),
+                    Ordering::Equal => {}
+                }
+            }
+        }
+
+        match self.tag_count.cmp(&other.tag_count) {
+            Ordering::Greater => (
+                Ordering::Greater,
+                format!("{} tags over {}", self.tag_count, other.tag_count),
+            ),
+            Ordering::Less => (
+                Ordering::Less,
+                format!("{} tags over {}", other.tag_count, self.tag_count),
+            ),
+            Ordering::Equal => (Ordering::Equal, "equal quality, kept the first".to_string()),
+        }
+    }
+}
+
+fn tag_count(metadata: &AudioMetadata) -> usize {
+    [
+        metadata.title.is_some(),
+        metadata.artist.is_some(),
+        metadata.album.is_some(),
+        metadata.album_artist.is_some(),
+        metadata.year.is_some(),
+        metadata.genre.is_some(),
+        metadata.track.is_some(),
+        metadata.track_total.is_some(),
+        metadata.disc.is_some(),
+        metadata.disc_total.is_some(),
+        metadata.composer.is_some(),
+        metadata.conductor.is_some(),
+        metadata.performer.is_some(),
+        metadata.album_sort.is_some(),
+        metadata.artist_sort.is_some(),
+        metadata.original_date.is_some(),
+        metadata.label.is_some(),
+        metadata.catalog_number.is_some(),
+        metadata.isrc.is_some(),
+        metadata.bpm.is_some(),
+        metadata.key.is_some(),
+        metadata.comment.is_some(),
+        metadata.musicbrainz_recording_id.is_some(),
+        metadata.musicbrainz_release_id.is_some(),
+        metadata.musicbrainz_artist_id.is_some(),
+    ]
+    .into_iter()
+    .filter(|&present| present)
+    .count()
+}
@@ -0,0 +1,129 @@
+use crate::{
+    journal::now,
+    plan::{PlannedAction, PlannedOperation},
+};
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+pub const DEFAULT_QUARANTINE_DIR: &str = "_duplicates";
+pub const MANIFEST_FILE: &str = "manifest.jsonl";
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ManifestEntry {
+    pub file: PathBuf,
+    pub duplicate_of: PathBuf,
+    pub source: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub timestamp: u64,
+}
+
+/// Append-only list of quarantined files next to the files themselves, so a
+/// reviewer can see what each one duplicated. Opened on first use to avoid
+/// creating the quarantine folder for runs without duplicates.
+pub struct Manifest {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+}
+
+impl Manifest {
+    pub fn new(path: &Path) -> Manifest {
+        Manifest {
+            path: path.to_path_buf(),
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Records a quarantined file, or the file an overwrite set aside to make
+    /// room for a better copy. Other actions leave nothing in the folder.
+    pub fn record(&self, op: &PlannedOperation) -> io::Result<()> {
+        let (file, duplicate_of, source) = match &op.action {
+            PlannedAction::Quarantine {
+                target,
+                duplicate_of,
+            } => (target, duplicate_of, &op.source),
+            PlannedAction::Overwrite {
+                target,
+                replaces,
+                set_aside: Some(set_aside),
+            } => (set_aside, target, replaces),
+            _ => return Ok(()),
+        };
+
+        let entry = ManifestEntry {
+            file: file.clone(),
+            duplicate_of: duplicate_of.clone(),
+            source: source.clone(),
+            reason: op.reason.clone(),
+            timestamp: now(),
+        };
+        let line = serde_json::to_string(&entry).map_err(io::Error::other)?;
+
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        if let Some(file) = file.as_mut() {
+            writeln!(file, "{}", line)?;
+            file.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_files_set_aside_by_an_overwrite() {
+        let path =
+            std::env::temp_dir().join(format!("ufrume-manifest-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manifest = Manifest::new(&path);
+
+        let op = |action| PlannedOperation {
+            source: PathBuf::from("/in/new.flac"),
+            action,
+            fallback: false,
+            reason: None,
+        };
+        manifest
+            .record(&op(PlannedAction::Overwrite {
+                target: PathBuf::from("/out/a.flac"),
+                replaces: PathBuf::from("/out/a.mp3"),
+                set_aside: Some(PathBuf::from("/out/_duplicates/a.mp3")),
+            }))
+            .unwrap();
+        manifest
+            .record(&op(PlannedAction::Overwrite {
+                target: PathBuf::from("/out/b.flac"),
+                replaces: PathBuf::from("/out/b.mp3"),
+                set_aside: None,
+            }))
+            .unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let entries: Vec<ManifestEntry> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].file, Path::new("/out/_duplicates/a.mp3"));
+        assert_eq!(entries[0].duplicate_of, Path::new("/out/a.flac"));
+        assert_eq!(entries[0].source, Path::new("/out/a.mp3"));
+    }
+}
@@ -0,0 +1,255 @@
+use crate::{
+    config::TransferMode, properties::AudioProperties, scan::AudioMetadata, sniff::is_mismatched,
+};
+
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Copied,
+    Skipped,
+    Duplicate,
+    Failed,
+    Fallback,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Copied => "copied",
+            Outcome::Skipped => "skipped",
+            Outcome::Duplicate => "duplicate",
+            Outcome::Failed => "failed",
+            Outcome::Fallback => "fallback",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportEntry {
+    pub source: PathBuf,
+    pub target: Option<PathBuf>,
+    pub outcome: Outcome,
+    pub duplicate_of: Option<PathBuf>,
+    /// Where the file this one replaced was moved to.
+    pub set_aside: Option<PathBuf>,
+    pub reason: Option<String>,
+    pub error: Option<String>,
+    pub metadata: Option<AudioMetadata>,
+    /// Whether the extension does not fit the detected file type.
+    pub mismatched: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+    copied: usize,
+    skipped: usize,
+    duplicate: usize,
+    failed: usize,
+    fallback: usize,
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    mode: TransferMode,
+    summary: Summary,
+    files: &'a [ReportEntry],
+}
+
+/// Fills in the metadata each entry was organized with, looked up by source
+/// path, and flags the entries whose extension does not fit their contents.
+pub fn attach_metadata(entries: &mut [ReportEntry], music_files: &[(PathBuf, AudioMetadata)]) {
+    let metadata: HashMap<&Path, &AudioMetadata> = music_files
+        .iter()
+        .map(|(path, metadata)| (path.as_path(), metadata))
+        .collect();
+
+    for entry in entries {
+        if entry.metadata.is_none() {
+            entry.metadata = metadata.get(entry.source.as_path()).map(|m| (*m).clone());
+        }
+        entry.mismatched = entry
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.format.as_deref())
+            .is_some_and(|format| is_mismatched(&entry.source, format));
+    }
+}
+
+pub fn failed_entry(source: &Path, error: &str) -> ReportEntry {
+    ReportEntry {
+        source: source.to_path_buf(),
+        target: None,
+        outcome: Outcome::Failed,
+        duplicate_of: None,
+        set_aside: None,
+        reason: None,
+        error: Some(error.to_string()),
+        metadata: None,
+        mismatched: false,
+    }
+}
+
+/// Writes the report as CSV when the path ends in `.csv`, and as JSON otherwise.
+pub fn write_report(
+    path: &Path,
+    mode: TransferMode,
+    entries: &[ReportEntry],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let is_csv = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+
+    if is_csv {
+        write_csv(path, entries)
+    } else {
+        write_json(path, mode, entries)
+    }
+}
+
+fn write_json(
+    path: &Path,
+    mode: TransferMode,
+    entries: &[ReportEntry],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut summary = Summary::default();
+    for entry in entries {
+        match entry.outcome {
+            Outcome::Copied => summary.copied += 1,
+            Outcome::Skipped => summary.skipped += 1,
+            Outcome::Duplicate => summary.duplicate += 1,
+            Outcome::Failed => summary.failed += 1,
+            Outcome::Fallback => summary.fallback += 1,
+        }
+    }
+
+    let report = JsonReport {
+        mode,
+        summary,
+        files: entries,
+    };
+    fs::write(path, serde_json::to_string_pretty(&report)?)?;
+
+    Ok(())
+}
+
+fn write_csv(path: &Path, entries: &[ReportEntry]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record([
+        "source",
+        "target",
+        "outcome",
+        "duplicate_of",
+        "set_aside",
+        "reason",
+        "error",
+        "title",
+        "artist",
+        "album",
+        "album_artist",
+        "year",
+        "genre",
+        "track",
+        "track_total",
+        "disc",
+        "disc_total",
+        "composer",
+        "conductor",
+        "performer",
+        "album_sort",
+        "artist_sort",
+        "original_date",
+        "label",
+        "catalog_number",
+        "isrc",
+        "bpm",
+        "key",
+        "comment",
+        "musicbrainz_recording_id",
+        "musicbrainz_release_id",
+        "musicbrainz_artist_id",
+        "codec",
+        "bitrate",
+        "sample_rate",
+        "bit_depth",
+        "channels",
+        "duration",
+        "format",
+        "mismatched",
+        "inferred",
+    ])?;
+
+    let path_field = |path: &Option<PathBuf>| {
+        path.as_ref()
+            .map(|path| path.display().to_string())
+            .unwrap_or_default()
+    };
+    let field = |value: Option<String>| value.unwrap_or_default();
+
+    for entry in entries {
+        let metadata = entry.metadata.as_ref();
+        let text = |get: fn(&AudioMetadata) -> &Option<String>| {
+            field(metadata.and_then(|m| get(m).clone()))
+        };
+        let number = |get: fn(&AudioMetadata) -> Option<i64>| {
+            field(metadata.and_then(get).map(|n| n.to_string()))
+        };
+        let property = |get: fn(&AudioProperties) -> Option<String>| {
+            field(metadata.and_then(|m| m.properties.as_ref()).and_then(get))
+        };
+
+        writer.write_record([
+            entry.source.display().to_string(),
+            path_field(&entry.target),
+            entry.outcome.as_str().to_string(),
+            path_field(&entry.duplicate_of),
+            path_field(&entry.set_aside),
+            field(entry.reason.clone()),
+            field(entry.error.clone()),
+            text(|m| &m.title),
+            text(|m| &m.artist),
+            text(|m| &m.album),
+            text(|m| &m.album_artist),
+            number(|m| m.year.map(i64::from)),
+            text(|m| &m.genre),
+            number(|m| m.track.map(i64::from)),
+            number(|m| m.track_total.map(i64::from)),
+            number(|m| m.disc.map(i64::from)),
+            number(|m| m.disc_total.map(i64::from)),
+            text(|m| &m.composer),
+            text(|m| &m.conductor),
+            text(|m| &m.performer),
+            text(|m| &m.album_sort),
+            text(|m| &m.artist_sort),
+            text(|m| &m.original_date),
+            text(|m| &m.label),
+            text(|m| &m.catalog_number),
+            text(|m| &m.isrc),
+            number(|m| m.bpm.map(i64::from)),
+            text(|m| &m.key),
+            text(|m| &m.comment),
+            text(|m| &m.musicbrainz_recording_id),
+            text(|m| &m.musicbrainz_release_id),
+            text(|m| &m.musicbrainz_artist_id),
+            property(|p| Some(p.codec.clone())),
+            property(|p| p.bitrate.map(|n| n.to_string())),
+            property(|p| p.sample_rate.map(|n| n.to_string())),
+            property(|p| p.bit_depth.map(|n| n.to_string())),
+            property(|p| p.channels.map(|n| n.to_string())),
+            property(|p| p.duration.map(|seconds| format!("{:.3}", seconds))),
+            text(|m| &m.format),
+            entry.mismatched.to_string(),
+            field(metadata.map(|m| m.inferred.join(","))),
+        ])?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
@@ -1,12 +1,21 @@
+use crate::{
+    cache::{FileStamp, ScanCache, file_stamp},
+    properties::{AudioProperties, read_properties},
+    sniff::{detect_format, is_mismatched},
+    tags::{read_extended_tags, read_tags},
+};
+
 use audiotags::Tag;
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use walkdir::WalkDir;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct AudioMetadata {
     pub title: Option<String>,
     pub artist: Option<String>,
@@ -15,34 +24,72 @@ pub struct AudioMetadata {
     pub year: Option<i32>,
     pub genre: Option<String>,
     pub track: Option<u16>,
+    pub track_total: Option<u16>,
+    pub disc: Option<u16>,
+    pub disc_total: Option<u16>,
+    pub composer: Option<String>,
+    pub conductor: Option<String>,
+    pub performer: Option<String>,
+    pub album_sort: Option<String>,
+    pub artist_sort: Option<String>,
+    pub original_date: Option<String>,
+    pub label: Option<String>,
+    pub catalog_number: Option<String>,
+    pub isrc: Option<String>,
+    pub bpm: Option<u16>,
+    pub key: Option<String>,
+    pub comment: Option<String>,
+    pub musicbrainz_recording_id: Option<String>,
+    pub musicbrainz_release_id: Option<String>,
+    pub musicbrainz_artist_id: Option<String>,
+    pub properties: Option<AudioProperties>,
+    /// The file type the contents were detected as, by its usual extension.
+    pub format: Option<String>,
+    /// The fields filled in from the file's path rather than its tags.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inferred: Vec<String>,
+}
+
+impl AudioMetadata {
+    /// The year of the original release, taken from the start of the
+    /// original date.
+    pub fn original_year(&self) -> Option<i32> {
+        let date = self.original_date.as_deref()?;
+        date.get(..4)?.parse().ok()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ScanResult {
+    pub files: Vec<(PathBuf, AudioMetadata)>,
+    pub failures: Vec<(PathBuf, String)>,
 }
 
 pub fn scan_for_music(
-    input_dir: &PathBuf,
-) -> Result<Vec<(PathBuf, AudioMetadata)>, Box<dyn std::error::Error>> {
-    let music_extensions = ["mp3", "flac", "m4a", "wav", "ogg", "aac"];
+    input_dir: &Path,
+    extensions: &[String],
+    cache: &mut ScanCache,
+) -> Result<ScanResult, Box<dyn std::error::Error>> {
+    let root = std::path::absolute(input_dir)?;
 
-    let music_file_paths: Vec<PathBuf> = WalkDir::new(input_dir)
+    let music_file_paths: Vec<PathBuf> = WalkDir::new(&root)
         .follow_links(false)
         .into_iter()
         .filter_map(|e| e.ok())
         .filter_map(|entry| {
             let path = entry.path();
-            if path.is_file() {
-                if let Some(extension) = path.extension() {
-                    if let Some(ext_str) = extension.to_str() {
-                        if music_extensions.contains(&ext_str.to_lowercase().as_str()) {
-                            return Some(path.to_path_buf());
-                        }
-                    }
-                }
+            if path.is_file() && is_music_file(path, extensions) {
+                return Some(path.to_path_buf());
             }
             None
         })
         .collect();
 
+    let seen: HashSet<&Path> = music_file_paths.iter().map(PathBuf::as_path).collect();
+    cache.retain_under(&root, |path| seen.contains(path));
+
     if music_file_paths.is_empty() {
-        return Ok(Vec::new());
+        return Ok(ScanResult::default());
     }
 
     let thread_count = rayon::current_num_threads();
@@ -63,19 +110,31 @@ pub fn scan_for_music(
 
     let successful_extractions = Arc::new(Mutex::new(0));
     let failed_extractions = Arc::new(Mutex::new(0));
+    let cached_extractions = Arc::new(Mutex::new(0));
 
-    let results: Vec<Option<(PathBuf, AudioMetadata)>> = music_file_paths
+    let shared_cache = &*cache;
+    type Extraction = Result<(PathBuf, AudioMetadata, Option<FileStamp>), (PathBuf, String)>;
+    let results: Vec<Extraction> = music_file_paths
         .par_iter()
         .map(|path| {
             if let Some(filename) = path.file_name() {
                 pb.set_message(filename.to_string_lossy().to_string());
             }
 
+            let stamp = file_stamp(path);
+            if let Some(stamp) = stamp
+                && let Some(metadata) = shared_cache.get(path, stamp)
+            {
+                *cached_extractions.lock().unwrap() += 1;
+                pb.inc(1);
+                return Ok((path.clone(), metadata.clone(), None));
+            }
+
             match extract_metadata(path) {
                 Ok(metadata) => {
                     *successful_extractions.lock().unwrap() += 1;
                     pb.inc(1);
-                    Some((path.clone(), metadata))
+                    Ok((path.clone(), metadata, stamp))
                 }
                 Err(err) => {
                     eprintln!(
@@ -85,7 +144,7 @@ pub fn scan_for_music(
                     );
                     *failed_extractions.lock().unwrap() += 1;
                     pb.inc(1);
-                    None
+                    Err((path.clone(), err.to_string()))
                 }
             }
         })
@@ -95,42 +154,121 @@ pub fn scan_for_music(
 
     let duration = start_time.elapsed();
 
-    let music_files: Vec<(PathBuf, AudioMetadata)> =
-        results.into_iter().filter_map(|r| r).collect();
+    let mut music_files = Vec::new();
+    let mut failures = Vec::new();
+    for result in results {
+        match result {
+            Ok((path, metadata, stamp)) => {
+                if let Some(stamp) = stamp {
+                    cache.insert(path.clone(), stamp, metadata.clone());
+                }
+                music_files.push((path, metadata));
+            }
+            Err(failure) => failures.push(failure),
+        }
+    }
+
+    let cached_count = *cached_extractions.lock().unwrap();
+    let cached = if cached_count > 0 {
+        format!(" ({} cached)", cached_count)
+    } else {
+        String::new()
+    };
 
     let failed_count = *failed_extractions.lock().unwrap();
     if failed_count > 0 {
         println!(
-            "  {} files processed, {} failed in {:.2}s",
+            "  {} files processed{}, {} failed in {:.2}s",
             music_files.len(),
+            cached,
             failed_count,
             duration.as_secs_f64()
         );
     } else {
         println!(
-            "  {} files processed in {:.2}s",
+            "  {} files processed{} in {:.2}s",
             music_files.len(),
+            cached,
             duration.as_secs_f64()
         );
     }
 
-    Ok(music_files)
+    Ok(ScanResult {
+        files: music_files,
+        failures,
+    })
 }
 
-fn extract_metadata(path: &Path) -> Result<AudioMetadata, Box<dyn std::error::Error>> {
-    let tag = Tag::default().read_from_path(path)?;
+pub fn is_music_file(path: &Path, extensions: &[String]) -> bool {
+    let is_listed = |name: &str| {
+        extensions
+            .iter()
+            .any(|extension| extension.trim_start_matches('.').eq_ignore_ascii_case(name))
+    };
+
+    match path.extension() {
+        Some(extension) => extension.to_str().is_some_and(is_listed),
+        // Some old rippers left the extension off, so look at the contents.
+        None => detect_format(path).ok().flatten().is_some_and(is_listed),
+    }
+}
+
+/// The extensions `audiotags` reads. Everything else goes through our own
+/// readers in `tags`.
+const AUDIOTAGS_EXTENSIONS: [&str; 7] = ["mp3", "flac", "m4a", "m4b", "m4p", "m4v", "mp4"];
+
+pub fn extract_metadata(path: &Path) -> Result<AudioMetadata, Box<dyn std::error::Error>> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase)
+        .unwrap_or_default();
 
-    Ok(AudioMetadata {
+    // The contents decide how to read the file, since the extension can be
+    // wrong or missing. `audiotags` only goes by the extension, so it is left
+    // to files whose extension it knows and which agrees with the contents.
+    let detected = detect_format(path).ok().flatten();
+    let format = detected.unwrap_or(extension.as_str());
+    let mismatched = detected.is_some_and(|detected| is_mismatched(path, detected));
+
+    // A file without a tag is untagged rather than broken, so when `audiotags`
+    // finds none our own readers get a go and return empty metadata instead.
+    let tag = if mismatched || !AUDIOTAGS_EXTENSIONS.contains(&extension.as_str()) {
+        None
+    } else {
+        Tag::default().read_from_path(path).ok()
+    };
+
+    let Some(tag) = tag else {
+        let mut metadata = read_tags(path, format)?;
+        metadata.album_artist = metadata.album_artist.as_deref().map(extract_first_artist);
+        metadata.properties = read_properties(path, format).ok();
+        metadata.format = detected.map(str::to_string);
+        return Ok(metadata);
+    };
+
+    let mut metadata = AudioMetadata {
         title: tag.title().map(str::to_string),
         artist: tag
             .artists()
             .and_then(|artists| artists.first().map(|s| s.to_string())),
         album: tag.album_title().map(str::to_string),
-        album_artist: tag.album_artist().map(|s| extract_first_artist(s)),
+        album_artist: tag.album_artist().map(extract_first_artist),
         year: tag.year(),
         genre: tag.genre().map(str::to_string),
-        track: tag.track().0.map(|t| t as u16),
-    })
+        track: tag.track_number(),
+        track_total: tag.total_tracks(),
+        disc: tag.disc_number(),
+        disc_total: tag.total_discs(),
+        format: detected.map(str::to_string),
+        ..Default::default()
+    };
+    // The common fields are enough to organize by, so a failure here only
+    // leaves the extended ones empty.
+    let _ = read_extended_tags(path, &mut metadata);
+    metadata.properties = read_properties(path, format).ok();
+
+    Ok(metadata)
 }
 
 fn extract_first_artist(artist_string: &str) -> String {
@@ -142,10 +280,10 @@ fn extract_first_artist(artist_string: &str) -> String {
     let mut earliest_pos = artist_string.len();
 
     for delimiter in &delimiters {
-        if let Some(pos) = artist_string.find(delimiter) {
-            if pos < earliest_pos {
-                earliest_pos = pos;
-            }
+        if let Some(pos) = artist_string.find(delimiter)
+            && pos < earliest_pos
+        {
+            earliest_pos = pos;
         }
     }
 
@@ -0,0 +1,135 @@
+use crate::{
+    audiohash::{read_up_to, skip_id3v2},
+    tags::ASF_HEADER,
+};
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Seek, SeekFrom},
+    path::Path,
+};
+
+/// Extensions that name the same container, so a file under any of them
+/// matches its contents.
+const EQUIVALENT_EXTENSIONS: [&[&str]; 4] = [
+    &["m4a", "m4b", "m4p", "m4v", "mp4"],
+    &["ogg", "oga", "opus"],
+    &["aiff", "aif", "aifc"],
+    &["wma", "asf"],
+];
+
+/// Detects the file type from its first bytes, past any ID3v2 tag, and
+/// returns the usual extension for it. `None` means the contents are not a
+/// format we know.
+pub fn detect_format(path: &Path) -> io::Result<Option<&'static str>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let start = skip_id3v2(&mut file)?;
+    file.seek(SeekFrom::Start(start))?;
+
+    let mut magic = [0u8; 64];
+    let read = read_up_to(&mut file, &mut magic)?;
+    Ok(format_of(&magic[..read], start > 0))
+}
+
+/// The format of a file starting with `magic`, which comes right after an
+/// ID3v2 tag when `has_id3` is set.
+fn format_of(magic: &[u8], has_id3: bool) -> Option<&'static str> {
+    let format = if magic.starts_with(b"fLaC") {
+        "flac"
+    } else if magic.starts_with(b"OggS") {
+        // The first packet follows the page header and its segment table.
+        let packet = magic.get(26).map(|&segments| 27 + segments as usize);
+        let is_opus = packet
+            .and_then(|packet| magic.get(packet..packet + 8))
+            .is_some_and(|packet| packet == b"OpusHead");
+        if is_opus { "opus" } else { "ogg" }
+    } else if magic.get(4..8) == Some(b"ftyp") {
+        "m4a"
+    } else if magic.starts_with(b"RIFF") && magic.get(8..12) == Some(b"WAVE") {
+        "wav"
+    } else if magic.starts_with(b"FORM") && matches!(magic.get(8..12), Some(b"AIFF" | b"AIFC")) {
+        "aiff"
+    } else if magic.starts_with(b"MAC ") {
+        "ape"
+    } else if magic.starts_with(b"wvpk") {
+        "wv"
+    } else if magic.starts_with(b"MPCK") || magic.starts_with(b"MP+") {
+        "mpc"
+    } else if magic.starts_with(b"DSD ") {
+        "dsf"
+    } else if magic.starts_with(b"FRM8") {
+        "dff"
+    } else if magic.starts_with(&ASF_HEADER) {
+        "wma"
+    } else if let [0xFF, second, ..] = magic
+        && second & 0xE0 == 0xE0
+    {
+        // An MPEG frame sync. ADTS (raw AAC) marks itself with layer 0.
+        if second & 0x06 == 0 { "aac" } else { "mp3" }
+    } else if has_id3 {
+        // An ID3v2 tag in front of something else is almost always an MP3
+        // with padding or junk before the first frame.
+        "mp3"
+    } else {
+        return None;
+    };
+
+    Some(format)
+}
+
+/// Whether `path` has no extension, or one that does not fit the detected
+/// format.
+pub fn is_mismatched(path: &Path, format: &str) -> bool {
+    let Some(extension) = path.extension().and_then(|extension| extension.to_str()) else {
+        return true;
+    };
+    let extension = extension.to_lowercase();
+
+    let fits = extension == format
+        || EQUIVALENT_EXTENSIONS
+            .iter()
+            .any(|group| group.contains(&extension.as_str()) && group.contains(&format));
+    !fits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ogg_page(packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS".to_vec();
+        page.extend([0, 2]);
+        page.extend([0u8; 20]);
+        page.push(1);
+        page.push(packet.len() as u8);
+        page.extend(packet);
+        page
+    }
+
+    #[test]
+    fn tells_opus_from_other_ogg() {
+        assert_eq!(
+            format_of(&ogg_page(b"OpusHead\x01\x02"), false),
+            Some("opus")
+        );
+        assert_eq!(format_of(&ogg_page(b"\x01vorbis"), false), Some("ogg"));
+        // A page cut off before the packet is still Ogg.
+        assert_eq!(format_of(&ogg_page(b"OpusHead")[..30], false), Some("ogg"));
+    }
+
+    #[test]
+    fn tells_adts_from_mpeg_audio() {
+        assert_eq!(format_of(&[0xFF, 0xF1, 0x50, 0x80], false), Some("aac"));
+        assert_eq!(format_of(&[0xFF, 0xFB, 0x90, 0x64], false), Some("mp3"));
+        assert_eq!(format_of(&[0xFF, 0x00], false), None);
+        assert_eq!(format_of(&[0x00, 0x00], true), Some("mp3"));
+    }
+
+    #[test]
+    fn equivalent_extensions_fit() {
+        assert!(!is_mismatched(Path::new("a.M4B"), "m4a"));
+        assert!(!is_mismatched(Path::new("a.ogg"), "opus"));
+        assert!(is_mismatched(Path::new("a.mp3"), "flac"));
+        assert!(is_mismatched(Path::new("a"), "flac"));
+    }
+}
@@ -0,0 +1,723 @@
+use crate::scan::AudioMetadata;
+
+use id3::TagLike;
+use mp4ameta::{Fourcc, FreeformIdent, ident};
+use std::{
+    fs::File,
+    io::{Cursor, Read, Seek, SeekFrom},
+    path::Path,
+};
+use symphonia::core::{
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::{MetadataOptions, MetadataRevision, StandardTagKey},
+    probe::Hint,
+};
+
+const MUSICBRAINZ_UFID_OWNER: &str = "http://musicbrainz.org";
+
+const APE_PREAMBLE: &[u8; 8] = b"APETAGEX";
+const APE_FOOTER_LEN: u64 = 32;
+const ID3V1_LEN: u64 = 128;
+
+pub const ASF_HEADER: [u8; 16] = [
+    0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62, 0xCE, 0x6C,
+];
+const ASF_CONTENT_DESCRIPTION: [u8; 16] = [
+    0x33, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62, 0xCE, 0x6C,
+];
+const ASF_EXTENDED_CONTENT_DESCRIPTION: [u8; 16] = [
+    0x40, 0xA4, 0xD0, 0xD2, 0x07, 0xE3, 0xD2, 0x11, 0x97, 0xF0, 0x00, 0xA0, 0xC9, 0x5E, 0xA8, 0x50,
+];
+
+/// Tag fields under their Vorbis comment names, which is what the formats
+/// without a crate of their own are translated to.
+#[derive(Debug, Default)]
+struct Fields(Vec<(String, String)>);
+
+impl Fields {
+    fn push(&mut self, name: &str, value: &str) {
+        self.0.push((name.to_uppercase(), value.to_string()));
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        self.0
+            .iter()
+            .filter(|(key, _)| key == name)
+            .find_map(|(_, value)| clean(value))
+    }
+}
+
+/// Reads every field from the files `audiotags` cannot open, going by
+/// `format` (the detected file type) rather than the extension: Ogg Vorbis and
+/// Opus comments, APEv2 tags (Monkey's Audio, WavPack and Musepack), ASF
+/// attributes (WMA), ID3v2 tags in MP3, WAV, AIFF, AAC and DSD files, and
+/// FLAC and MP4 files under the wrong extension. WAV and AIFF files without an
+/// ID3 chunk fall back to their native text chunks. A file without any tag
+/// reads as empty metadata rather than an error.
+pub fn read_tags(path: &Path, format: &str) -> Result<AudioMetadata, Box<dyn std::error::Error>> {
+    let mut metadata = AudioMetadata::default();
+    match format {
+        "flac" => apply_fields(&read_flac_comments(path)?, &mut metadata),
+        "m4a" | "m4b" | "m4p" | "m4v" | "mp4" => {
+            apply_mp4(&mp4ameta::Tag::read_from_path(path)?, &mut metadata)
+        }
+        "ape" | "wv" | "mpc" => apply_fields(&read_ape_tag(path)?, &mut metadata),
+        "wma" | "asf" => apply_fields(&read_asf(path)?, &mut metadata),
+        "dsf" => {
+            if let Some(tag) = read_dsf_id3(path)? {
+                apply_id3(&tag, &mut metadata);
+            }
+        }
+        "dff" => read_dff(path, &mut metadata)?,
+        "mp3" | "wav" | "aif" | "aiff" | "aifc" | "aac" => match id3::Tag::read_from_path(path) {
+            Ok(tag) => apply_id3(&tag, &mut metadata),
+            Err(_) => apply_fields(&read_container_tags(path)?, &mut metadata),
+        },
+        _ => apply_fields(&read_container_tags(path)?, &mut metadata),
+    }
+
+    Ok(metadata)
+}
+
+/// Fills in the fields `audiotags` does not cover by reading the
+/// format-specific frames directly: ID3v2 text and TXXX frames, FLAC Vorbis
+/// comments and MP4 freeform atoms. The names follow the MusicBrainz Picard
+/// tag mapping, which is what most taggers write.
+pub fn read_extended_tags(
+    path: &Path,
+    metadata: &mut AudioMetadata,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase)
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "mp3" => read_id3(path, metadata),
+        "flac" => read_vorbis_comments(path, metadata),
+        "m4a" | "m4b" | "m4p" | "m4v" | "mp4" => read_mp4(path, metadata),
+        _ => Ok(()),
+    }
+}
+
+fn read_id3(path: &Path, metadata: &mut AudioMetadata) -> Result<(), Box<dyn std::error::Error>> {
+    let tag = id3::Tag::read_from_path(path)?;
+    read_id3_frames(&tag, metadata);
+    Ok(())
+}
+
+fn apply_id3(tag: &id3::Tag, metadata: &mut AudioMetadata) {
+    let number = |value: Option<u32>| value.and_then(|n| u16::try_from(n).ok());
+
+    metadata.title = tag.title().and_then(clean);
+    metadata.artist = tag
+        .artists()
+        .and_then(|artists| artists.first().and_then(|artist| clean(artist)));
+    metadata.album = tag.album().and_then(clean);
+    metadata.album_artist = tag.album_artist().and_then(clean);
+    metadata.year = tag
+        .year()
+        .or_else(|| tag.date_recorded().map(|date| date.year));
+    metadata.genre = tag.genre().and_then(clean);
+    metadata.track = number(tag.track());
+    metadata.track_total = number(tag.total_tracks());
+    metadata.disc = number(tag.disc());
+    metadata.disc_total = number(tag.total_discs());
+    read_id3_frames(tag, metadata);
+}
+
+fn read_id3_frames(tag: &id3::Tag, metadata: &mut AudioMetadata) {
+    let text = |id: &str| {
+        tag.get(id)
+            .and_then(|frame| frame.content().text())
+            .and_then(clean)
+    };
+    let extended = |description: &str| {
+        tag.extended_texts()
+            .find(|text| text.description.eq_ignore_ascii_case(description))
+            .and_then(|text| clean(&text.value))
+    };
+
+    metadata.composer = text("TCOM");
+    metadata.conductor = text("TPE3");
+    metadata.performer = extended("PERFORMER");
+    metadata.album_sort = text("TSOA");
+    metadata.artist_sort = text("TSOP");
+    metadata.original_date = text("TDOR")
+        .or_else(|| text("TORY"))
+        .or_else(|| extended("originalyear"));
+    metadata.label = text("TPUB");
+    metadata.catalog_number = extended("CATALOGNUMBER");
+    metadata.isrc = text("TSRC");
+    metadata.bpm = text("TBPM").as_deref().and_then(parse_bpm);
+    metadata.key = text("TKEY");
+    metadata.comment = tag
+        .comments()
+        .find(|comment| comment.description.is_empty())
+        .or_else(|| tag.comments().next())
+        .and_then(|comment| clean(&comment.text));
+    metadata.musicbrainz_recording_id = tag
+        .unique_file_identifiers()
+        .find(|ufid| ufid.owner_identifier == MUSICBRAINZ_UFID_OWNER)
+        .and_then(|ufid| clean(&String::from_utf8_lossy(&ufid.identifier)));
+    metadata.musicbrainz_release_id = extended("MusicBrainz Album Id");
+    metadata.musicbrainz_artist_id = extended("MusicBrainz Artist Id");
+}
+
+fn read_vorbis_comments(
+    path: &Path,
+    metadata: &mut AudioMetadata,
+) -> Result<(), Box<dyn std::error::Error>> {
+    apply_extended_fields(&read_flac_comments(path)?, metadata);
+    Ok(())
+}
+
+fn read_flac_comments(path: &Path) -> Result<Fields, Box<dyn std::error::Error>> {
+    let tag = metaflac::Tag::read_from_path(path)?;
+    let mut fields = Fields::default();
+    if let Some(comments) = tag.vorbis_comments() {
+        for (name, values) in &comments.comments {
+            for value in values {
+                fields.push(name, value);
+            }
+        }
+    }
+    Ok(fields)
+}
+
+fn apply_fields(fields: &Fields, metadata: &mut AudioMetadata) {
+    let (track, track_total) =
+        split_position(fields.get("TRACKNUMBER").or_else(|| fields.get("TRACK")));
+    let (disc, disc_total) =
+        split_position(fields.get("DISCNUMBER").or_else(|| fields.get("DISC")));
+    let total = |names: [&str; 2]| {
+        names
+            .into_iter()
+            .find_map(|name| fields.get(name))
+            .and_then(|value| value.parse().ok())
+    };
+
+    metadata.title = fields.get("TITLE");
+    metadata.artist = fields.get("ARTIST");
+    metadata.album = fields.get("ALBUM");
+    metadata.album_artist = fields
+        .get("ALBUMARTIST")
+        .or_else(|| fields.get("ALBUM ARTIST"));
+    metadata.year = fields
+        .get("DATE")
+        .or_else(|| fields.get("YEAR"))
+        .and_then(|date| date.get(..4)?.parse().ok());
+    metadata.genre = fields.get("GENRE");
+    metadata.track = track;
+    metadata.track_total = total(["TRACKTOTAL", "TOTALTRACKS"]).or(track_total);
+    metadata.disc = disc;
+    metadata.disc_total = total(["DISCTOTAL", "TOTALDISCS"]).or(disc_total);
+    apply_extended_fields(fields, metadata);
+}
+
+fn apply_extended_fields(fields: &Fields, metadata: &mut AudioMetadata) {
+    let get = |name: &str| fields.get(name);
+
+    metadata.composer = get("COMPOSER");
+    metadata.conductor = get("CONDUCTOR");
+    metadata.performer = get("PERFORMER");
+    metadata.album_sort = get("ALBUMSORT");
+    metadata.artist_sort = get("ARTISTSORT");
+    metadata.original_date = get("ORIGINALDATE").or_else(|| get("ORIGINALYEAR"));
+    metadata.label = get("LABEL")
+        .or_else(|| get("ORGANIZATION"))
+        .or_else(|| get("PUBLISHER"));
+    metadata.catalog_number = get("CATALOGNUMBER");
+    metadata.isrc = get("ISRC");
+    metadata.bpm = get("BPM").as_deref().and_then(parse_bpm);
+    metadata.key = get("KEY").or_else(|| get("INITIALKEY"));
+    metadata.comment = get("COMMENT").or_else(|| get("DESCRIPTION"));
+    metadata.musicbrainz_recording_id = get("MUSICBRAINZ_TRACKID");
+    metadata.musicbrainz_release_id = get("MUSICBRAINZ_ALBUMID");
+    metadata.musicbrainz_artist_id = get("MUSICBRAINZ_ARTISTID");
+}
+
+/// Splits a "3/12" style position into the number and the total.
+fn split_position(value: Option<String>) -> (Option<u16>, Option<u16>) {
+    let Some(value) = value else {
+        return (None, None);
+    };
+    let (number, total) = match value.split_once('/') {
+        Some((number, total)) => (number, Some(total)),
+        None => (value.as_str(), None),
+    };
+
+    (
+        number.trim().parse().ok(),
+        total.and_then(|total| total.trim().parse().ok()),
+    )
+}
+
+fn read_mp4(path: &Path, metadata: &mut AudioMetadata) -> Result<(), Box<dyn std::error::Error>> {
+    let tag = mp4ameta::Tag::read_from_path(path)?;
+    read_mp4_atoms(&tag, metadata);
+    Ok(())
+}
+
+fn apply_mp4(tag: &mp4ameta::Tag, metadata: &mut AudioMetadata) {
+    metadata.title = tag.title().and_then(clean);
+    metadata.artist = tag.artist().and_then(clean);
+    metadata.album = tag.album().and_then(clean);
+    metadata.album_artist = tag.album_artist().and_then(clean);
+    metadata.year = tag.year().and_then(|date| date.get(..4)?.parse().ok());
+    metadata.genre = tag.genre().and_then(clean);
+    metadata.track = tag.track_number();
+    metadata.track_total = tag.total_tracks();
+    metadata.disc = tag.disc_number();
+    metadata.disc_total = tag.total_discs();
+    read_mp4_atoms(tag, metadata);
+}
+
+fn read_mp4_atoms(tag: &mp4ameta::Tag, metadata: &mut AudioMetadata) {
+    let atom = |fourcc: Fourcc| tag.strings_of(&fourcc).next().and_then(clean);
+    let freeform = |name: &str| {
+        tag.strings_of(&FreeformIdent::new(ident::APPLE_ITUNES_MEAN, name))
+            .next()
+            .and_then(clean)
+    };
+
+    metadata.composer = atom(ident::COMPOSER);
+    metadata.conductor = freeform("CONDUCTOR");
+    metadata.performer = freeform("PERFORMER");
+    metadata.album_sort = atom(Fourcc(*b"soal"));
+    metadata.artist_sort = atom(Fourcc(*b"soar"));
+    metadata.original_date = freeform("ORIGINALDATE").or_else(|| freeform("ORIGINALYEAR"));
+    metadata.label = freeform("LABEL");
+    metadata.catalog_number = freeform("CATALOGNUMBER");
+    metadata.isrc = freeform("ISRC");
+    metadata.bpm = tag.bpm();
+    metadata.key = freeform("initialkey");
+    metadata.comment = atom(ident::COMMENT);
+    metadata.musicbrainz_recording_id = freeform("MusicBrainz Track Id");
+    metadata.musicbrainz_release_id = freeform("MusicBrainz Album Id");
+    metadata.musicbrainz_artist_id = freeform("MusicBrainz Artist Id");
+}
+
+/// Reads the tags symphonia picks up while probing: Vorbis and Opus comments
+/// in Ogg, a RIFF INFO chunk ahead of the WAV audio and any ID3v2 tag in front
+/// of the stream.
+fn read_container_tags(path: &Path) -> Result<Fields, Box<dyn std::error::Error>> {
+    let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let mut probed = symphonia::default::get_probe().format(
+        &hint,
+        source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+
+    let mut fields = Fields::default();
+    let mut add = |revision: &MetadataRevision| {
+        for tag in revision.tags() {
+            let name = tag.std_key.and_then(vorbis_name).unwrap_or(&tag.key);
+            fields.push(name, &tag.value.to_string());
+        }
+    };
+    if let Some(mut metadata) = probed.metadata.get()
+        && let Some(revision) = metadata.skip_to_latest()
+    {
+        add(revision);
+    }
+    if let Some(revision) = probed.format.metadata().skip_to_latest() {
+        add(revision);
+    }
+
+    Ok(fields)
+}
+
+/// The Vorbis names of the keys symphonia recognises in formats that use
+/// names of their own, like the four-letter RIFF INFO ids.
+fn vorbis_name(key: StandardTagKey) -> Option<&'static str> {
+    let name = match key {
+        StandardTagKey::TrackTitle => "TITLE",
+        StandardTagKey::Artist => "ARTIST",
+        StandardTagKey::Album => "ALBUM",
+        StandardTagKey::AlbumArtist => "ALBUMARTIST",
+        StandardTagKey::Date => "DATE",
+        StandardTagKey::Genre => "GENRE",
+        StandardTagKey::TrackNumber => "TRACKNUMBER",
+        StandardTagKey::TrackTotal => "TRACKTOTAL",
+        StandardTagKey::DiscNumber => "DISCNUMBER",
+        StandardTagKey::DiscTotal => "DISCTOTAL",
+        StandardTagKey::Composer => "COMPOSER",
+        StandardTagKey::Comment => "COMMENT",
+        StandardTagKey::OriginalDate => "ORIGINALDATE",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Reads an APEv2 (or APEv1) tag from the end of the file, where it sits
+/// either last or in front of an ID3v1 tag.
+fn read_ape_tag(path: &Path) -> Result<Fields, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let length = file.metadata()?.len();
+    parse_ape_tag(&mut file, length)
+}
+
+fn parse_ape_tag(
+    file: &mut (impl Read + Seek),
+    length: u64,
+) -> Result<Fields, Box<dyn std::error::Error>> {
+    let mut fields = Fields::default();
+
+    for end in [length, length.saturating_sub(ID3V1_LEN)] {
+        if end < APE_FOOTER_LEN {
+            continue;
+        }
+
+        let mut footer = [0u8; APE_FOOTER_LEN as usize];
+        file.seek(SeekFrom::Start(end - APE_FOOTER_LEN))?;
+        file.read_exact(&mut footer)?;
+        if &footer[..8] != APE_PREAMBLE {
+            continue;
+        }
+
+        // The size counts the items and the footer, but not the optional header.
+        let size = u64::from(u32_le(&footer, 12).unwrap_or_default());
+        let count = u32_le(&footer, 16).unwrap_or_default();
+        let items_len = size
+            .checked_sub(APE_FOOTER_LEN)
+            .filter(|&len| len <= end - APE_FOOTER_LEN)
+            .ok_or("APE tag size does not fit the file")?;
+
+        let mut items = vec![0u8; items_len as usize];
+        file.seek(SeekFrom::Start(end - APE_FOOTER_LEN - items_len))?;
+        file.read_exact(&mut items)?;
+        parse_ape_items(&items, count, &mut fields);
+        break;
+    }
+
+    Ok(fields)
+}
+
+fn parse_ape_items(items: &[u8], count: u32, fields: &mut Fields) -> Option<()> {
+    let mut pos = 0;
+    for _ in 0..count {
+        let value_len = u32_le(items, pos)? as usize;
+        let flags = u32_le(items, pos + 4)?;
+        let key_start = pos + 8;
+        let key_len = items.get(key_start..)?.iter().position(|&b| b == 0)?;
+        let key = String::from_utf8_lossy(&items[key_start..key_start + key_len]);
+        let value_start = key_start + key_len + 1;
+        let value = items.get(value_start..value_start.checked_add(value_len)?)?;
+        pos = value_start + value_len;
+
+        // Bits 1 and 2 hold the item type, and zero means UTF-8 text. A text
+        // item holds several values separated by NUL.
+        if flags & 0b110 == 0 {
+            for value in String::from_utf8_lossy(value).split('\0') {
+                fields.push(&key, value);
+            }
+        }
+    }
+    Some(())
+}
+
+/// Reads the title and author from the ASF content description object and
+/// the `WM/` attributes from the extended content description object, the
+/// two places WMA taggers write to.
+fn read_asf(path: &Path) -> Result<Fields, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    parse_asf(&mut file, file_len)
+}
+
+fn parse_asf(file: &mut impl Read, file_len: u64) -> Result<Fields, Box<dyn std::error::Error>> {
+    let mut header = [0u8; 30];
+    file.read_exact(&mut header)?;
+    if header[..16] != ASF_HEADER {
+        return Err("not an ASF file".into());
+    }
+
+    let header_len = u64_le(&header, 16).unwrap_or_default();
+    let mut objects = vec![0u8; header_len.clamp(30, file_len) as usize - 30];
+    file.read_exact(&mut objects)?;
+
+    let mut fields = Fields::default();
+    let mut pos = 0;
+    while let (Some(guid), Some(size)) = (objects.get(pos..pos + 16), u64_le(&objects, pos + 16)) {
+        let Some(body) = usize::try_from(size)
+            .ok()
+            .filter(|&size| size >= 24)
+            .and_then(|size| objects.get(pos + 24..pos.checked_add(size)?))
+        else {
+            break;
+        };
+
+        if guid == ASF_CONTENT_DESCRIPTION {
+            parse_asf_content_description(body, &mut fields);
+        } else if guid == ASF_EXTENDED_CONTENT_DESCRIPTION {
+            parse_asf_extended_content_description(body, &mut fields);
+        }
+        pos += body.len() + 24;
+    }
+
+    Ok(fields)
+}
+
+fn parse_asf_content_description(body: &[u8], fields: &mut Fields) -> Option<()> {
+    let names = ["TITLE", "ARTIST", "COPYRIGHT", "COMMENT", "RATING"];
+    let mut pos = 10;
+    for (index, name) in names.into_iter().enumerate() {
+        let len = u16_le(body, index * 2)? as usize;
+        fields.push(name, &utf16_le(body.get(pos..pos + len)?));
+        pos += len;
+    }
+    Some(())
+}
+
+fn parse_asf_extended_content_description(body: &[u8], fields: &mut Fields) -> Option<()> {
+    let count = u16_le(body, 0)?;
+    let mut pos = 2;
+    for _ in 0..count {
+        let name_len = u16_le(body, pos)? as usize;
+        let name = utf16_le(body.get(pos + 2..pos + 2 + name_len)?);
+        pos += 2 + name_len;
+        let value_type = u16_le(body, pos)?;
+        let value_len = u16_le(body, pos + 2)? as usize;
+        let value = body.get(pos + 4..pos + 4 + value_len)?;
+        pos += 4 + value_len;
+
+        let value = match value_type {
+            0 => utf16_le(value),
+            3 => u32_le(value, 0)?.to_string(),
+            4 => u64_le(value, 0)?.to_string(),
+            5 => u16_le(value, 0)?.to_string(),
+            // Byte arrays (cover art) and booleans.
+            _ => continue,
+        };
+        let name = name.trim_end_matches('\0');
+        fields.push(asf_field_name(name).unwrap_or(name), &value);
+    }
+    Some(())
+}
+
+/// The Vorbis names of the `WM/` attributes, after the MusicBrainz Picard
+/// tag mapping.
+fn asf_field_name(name: &str) -> Option<&'static str> {
+    let name = match name {
+        "WM/AlbumTitle" => "ALBUM",
+        "WM/AlbumArtist" => "ALBUMARTIST",
+        "WM/Year" => "DATE",
+        "WM/Genre" => "GENRE",
+        "WM/TrackNumber" => "TRACKNUMBER",
+        "WM/PartOfSet" => "DISCNUMBER",
+        "WM/Composer" => "COMPOSER",
+        "WM/Conductor" => "CONDUCTOR",
+        "WM/AlbumSortOrder" => "ALBUMSORT",
+        "WM/ArtistSortOrder" => "ARTISTSORT",
+        "WM/OriginalReleaseTime" => "ORIGINALDATE",
+        "WM/OriginalReleaseYear" => "ORIGINALYEAR",
+        "WM/Publisher" => "LABEL",
+        "WM/CatalogNo" => "CATALOGNUMBER",
+        "WM/ISRC" => "ISRC",
+        "WM/BeatsPerMinute" => "BPM",
+        "WM/InitialKey" => "KEY",
+        "MusicBrainz/Track Id" => "MUSICBRAINZ_TRACKID",
+        "MusicBrainz/Album Id" => "MUSICBRAINZ_ALBUMID",
+        "MusicBrainz/Artist Id" => "MUSICBRAINZ_ARTISTID",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// DSF files point to an ID3v2 tag at the end of the file from their header.
+fn read_dsf_id3(path: &Path) -> Result<Option<id3::Tag>, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let mut header = [0u8; 28];
+    file.read_exact(&mut header)?;
+    if &header[..4] != b"DSD " {
+        return Err("not a DSF file".into());
+    }
+
+    let pointer = u64_le(&header, 20).unwrap_or_default();
+    if pointer == 0 {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(pointer))?;
+
+    Ok(Some(id3::Tag::read_from2(file)?))
+}
+
+/// DSDIFF has no tag of its own beyond the title and artist in its `DIIN`
+/// chunk, so most taggers add an unofficial `ID3 ` chunk. The ID3 tag wins
+/// when there are both.
+fn read_dff(path: &Path, metadata: &mut AudioMetadata) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let mut header = [0u8; 16];
+    file.read_exact(&mut header)?;
+    if &header[..4] != b"FRM8" || &header[12..] != b"DSD " {
+        return Err("not a DSDIFF file".into());
+    }
+
+    let mut fields = Fields::default();
+    let mut chunk = [0u8; 12];
+    while file.read_exact(&mut chunk).is_ok() {
+        let size = u64::from_be_bytes(chunk[4..].try_into()?);
+        match &chunk[..4] {
+            b"ID3 " => {
+                let mut tag = Vec::new();
+                (&mut file).take(size).read_to_end(&mut tag)?;
+                apply_id3(&id3::Tag::read_from2(Cursor::new(tag))?, metadata);
+                return Ok(());
+            }
+            b"DIIN" => {
+                let mut info = Vec::new();
+                (&mut file).take(size).read_to_end(&mut info)?;
+                parse_dff_info(&info, &mut fields);
+            }
+            _ => {
+                file.seek(SeekFrom::Current(i64::try_from(size)?))?;
+            }
+        }
+        // Chunks are padded to an even length.
+        if size % 2 == 1 {
+            file.seek(SeekFrom::Current(1))?;
+        }
+    }
+
+    apply_fields(&fields, metadata);
+    Ok(())
+}
+
+fn parse_dff_info(info: &[u8], fields: &mut Fields) -> Option<()> {
+    let mut pos = 0;
+    while let Some(id) = info.get(pos..pos + 4) {
+        let size = usize::try_from(u64::from_be_bytes(
+            info.get(pos + 4..pos + 12)?.try_into().ok()?,
+        ))
+        .ok()?;
+        let body = info.get(pos + 12..(pos + 12).checked_add(size)?)?;
+        // Text chunks start with the length of the text.
+        let text = || body.get(4..).map(String::from_utf8_lossy);
+        match id {
+            b"DITI" => fields.push("TITLE", &text()?),
+            b"DIAR" => fields.push("ARTIST", &text()?),
+            _ => {}
+        }
+        pos += 12 + size + size % 2;
+    }
+    Some(())
+}
+
+fn u16_le(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_le(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_le(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn utf16_le(data: &[u8]) -> String {
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn clean(value: &str) -> Option<String> {
+    let value = value.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// BPM is usually an integer, but some taggers write a decimal.
+fn parse_bpm(value: &str) -> Option<u16> {
+    let bpm: f64 = value.trim().parse().ok()?;
+    (bpm > 0.0 && bpm < f64::from(u16::MAX)).then(|| bpm.round() as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(text: &str) -> Vec<u8> {
+        text.encode_utf16()
+            .chain([0])
+            .flat_map(u16::to_le_bytes)
+            .collect()
+    }
+
+    fn asf_file(title: &str) -> Vec<u8> {
+        let title = utf16(title);
+        let mut body = Vec::new();
+        for len in [title.len(), 0, 0, 0, 0] {
+            body.extend((len as u16).to_le_bytes());
+        }
+        body.extend(&title);
+
+        let mut object = ASF_CONTENT_DESCRIPTION.to_vec();
+        object.extend((24 + body.len() as u64).to_le_bytes());
+        object.extend(body);
+
+        let mut file = ASF_HEADER.to_vec();
+        file.extend((30 + object.len() as u64).to_le_bytes());
+        file.extend(1u32.to_le_bytes());
+        file.extend([1, 2]);
+        file.extend(object);
+        file
+    }
+
+    #[test]
+    fn reads_asf_content_description() {
+        let file = asf_file("Song");
+        let fields = parse_asf(&mut Cursor::new(&file), file.len() as u64).unwrap();
+        assert_eq!(fields.get("TITLE").as_deref(), Some("Song"));
+    }
+
+    #[test]
+    fn truncated_asf_header_reads_no_fields() {
+        let mut file = asf_file("Song");
+        file.truncate(file.len() - 4);
+        let fields = parse_asf(&mut Cursor::new(&file), file.len() as u64).unwrap();
+        assert_eq!(fields.get("TITLE"), None);
+
+        file.truncate(20);
+        assert!(parse_asf(&mut Cursor::new(&file), file.len() as u64).is_err());
+    }
+
+    fn ape_footer(size: u32, count: u32) -> Vec<u8> {
+        let mut footer = APE_PREAMBLE.to_vec();
+        footer.extend(2000u32.to_le_bytes());
+        footer.extend(size.to_le_bytes());
+        footer.extend(count.to_le_bytes());
+        footer.extend([0u8; 12]);
+        footer
+    }
+
+    #[test]
+    fn reads_ape_items() {
+        let mut items = 5u32.to_le_bytes().to_vec();
+        items.extend(0u32.to_le_bytes());
+        items.extend(b"Title\0Hello");
+
+        let mut file = vec![0u8; 64];
+        file.extend(&items);
+        file.extend(ape_footer(32 + items.len() as u32, 1));
+        let fields = parse_ape_tag(&mut Cursor::new(&file), file.len() as u64).unwrap();
+        assert_eq!(fields.get("TITLE").as_deref(), Some("Hello"));
+    }
+
+    #[test]
+    fn zero_size_ape_footer_is_an_error() {
+        let mut file = vec![0u8; 64];
+        file.extend(ape_footer(0, 1));
+        assert!(parse_ape_tag(&mut Cursor::new(&file), file.len() as u64).is_err());
+    }
+}
@@ -0,0 +1,939 @@
+use std::fmt;
+
+pub const FIELDS: [&str; 35] = [
+    "artist",
+    "albumartist",
+    "title",
+    "album",
+    "year",
+    "track",
+    "tracktotal",
+    "disc",
+    "disctotal",
+    "genre",
+    "filename",
+    "composer",
+    "conductor",
+    "performer",
+    "albumsort",
+    "artistsort",
+    "originaldate",
+    "originalyear",
+    "label",
+    "catalognumber",
+    "isrc",
+    "bpm",
+    "key",
+    "comment",
+    "mbrecordingid",
+    "mbreleaseid",
+    "mbartistid",
+    "codec",
+    "container",
+    "bitrate",
+    "bitratemode",
+    "samplerate",
+    "bitdepth",
+    "channels",
+    "duration",
+];
+
+pub const NUMERIC_FIELDS: [&str; 12] = [
+    "year",
+    "track",
+    "tracktotal",
+    "disc",
+    "disctotal",
+    "originalyear",
+    "bpm",
+    "bitrate",
+    "samplerate",
+    "bitdepth",
+    "channels",
+    "duration",
+];
+
+/// Filter names. `title` is also a placeholder, so after the first item of a
+/// placeholder it is read as the filter.
+const FILTERS: [&str; 8] = [
+    "lower", "upper", "title", "truncate", "initial", "ascii", "trim", "replace",
+];
+
+#[derive(Debug)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+    Optional {
+        condition: Option<Condition>,
+        segments: Vec<Segment>,
+    },
+}
+
+#[derive(Debug)]
+struct Condition {
+    field: String,
+    comparison: Option<(Comparison, String)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+#[derive(Debug)]
+struct Placeholder {
+    alternatives: Vec<Alternative>,
+    filters: Vec<Filter>,
+}
+
+#[derive(Debug)]
+enum Alternative {
+    Field {
+        name: String,
+        format: Option<NumberFormat>,
+    },
+    Literal(String),
+}
+
+#[derive(Debug)]
+enum NumberFormat {
+    Pad { width: usize, zero: bool },
+    ShortYear,
+    FullYear,
+}
+
+#[derive(Debug)]
+enum Filter {
+    Lower,
+    Upper,
+    Title,
+    Truncate(usize),
+    Initial,
+    Ascii,
+    Trim,
+    Replace(String, String),
+}
+
+pub enum Value {
+    Text(String),
+    Number(i64),
+}
+
+#[derive(Debug)]
+pub struct TemplateError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+struct Item {
+    parts: Vec<String>,
+    quoted: bool,
+    column: usize,
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template, TemplateError> {
+        let mut parser = Parser {
+            chars: template.chars().collect(),
+            pos: 0,
+        };
+        let segments = parser.segments(None)?;
+
+        Ok(Template { segments })
+    }
+
+    /// Rejects templates that would place files outside the output directory,
+    /// either through an absolute path or a `..` component.
+    pub fn check_relative(template: &str) -> Result<(), TemplateError> {
+        let mut chars = template.chars();
+        let has_drive = matches!(
+            (chars.next(), chars.next()),
+            (Some(letter), Some(':')) if letter.is_ascii_alphabetic()
+        );
+        if template.starts_with('/') || has_drive {
+            return Err(TemplateError {
+                column: 1,
+                message: "template must be a relative path".to_string(),
+            });
+        }
+
+        let mut column = 1;
+        for component in template.split('/') {
+            if component.trim() == ".." {
+                return Err(TemplateError {
+                    column,
+                    message: "'..' is not allowed in a template".to_string(),
+                });
+            }
+            column += component.chars().count() + 1;
+        }
+
+        Ok(())
+    }
+
+    pub fn render(
+        &self,
+        resolve: impl Fn(&str) -> Option<Value>,
+        sanitize: impl Fn(&str) -> String,
+    ) -> Option<String> {
+        render_segments(&self.segments, &resolve, &sanitize)
+    }
+}
+
+fn render_segments(
+    segments: &[Segment],
+    resolve: &impl Fn(&str) -> Option<Value>,
+    sanitize: &impl Fn(&str) -> String,
+) -> Option<String> {
+    let mut result = String::new();
+
+    for segment in segments {
+        match segment {
+            Segment::Literal(text) => result.push_str(text),
+            Segment::Placeholder(placeholder) => {
+                let mut text = placeholder.resolve(resolve)?;
+                for filter in &placeholder.filters {
+                    text = filter.apply(&text);
+                }
+                result.push_str(&sanitize(&text));
+            }
+            Segment::Optional {
+                condition,
+                segments,
+            } => {
+                if condition
+                    .as_ref()
+                    .is_some_and(|condition| !condition.holds(resolve))
+                {
+                    continue;
+                }
+                if let Some(text) = render_segments(segments, resolve, sanitize) {
+                    result.push_str(&text);
+                }
+            }
+        }
+    }
+
+    Some(result)
+}
+
+impl Condition {
+    fn holds(&self, resolve: &impl Fn(&str) -> Option<Value>) -> bool {
+        let Some(value) = resolve(&self.field) else {
+            return false;
+        };
+        let text = format_value(value, None);
+
+        let Some((comparison, expected)) = &self.comparison else {
+            return !text.trim().is_empty();
+        };
+
+        if let (Ok(actual), Ok(expected)) = (text.trim().parse::<f64>(), expected.parse::<f64>()) {
+            return match comparison {
+                Comparison::Equal => actual == expected,
+                Comparison::NotEqual => actual != expected,
+                Comparison::Greater => actual > expected,
+                Comparison::GreaterOrEqual => actual >= expected,
+                Comparison::Less => actual < expected,
+                Comparison::LessOrEqual => actual <= expected,
+            };
+        }
+
+        match comparison {
+            Comparison::Equal => text == *expected,
+            Comparison::NotEqual => text != *expected,
+            _ => false,
+        }
+    }
+}
+
+impl Placeholder {
+    fn resolve(&self, resolve: &impl Fn(&str) -> Option<Value>) -> Option<String> {
+        self.alternatives.iter().find_map(|alternative| {
+            let text = match alternative {
+                Alternative::Field { name, format } => {
+                    format_value(resolve(name)?, format.as_ref())
+                }
+                Alternative::Literal(text) => text.clone(),
+            };
+            (!text.trim().is_empty()).then_some(text)
+        })
+    }
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn column(&self) -> usize {
+        self.pos + 1
+    }
+
+    fn error(&self, message: impl Into<String>) -> TemplateError {
+        TemplateError {
+            column: self.column(),
+            message: message.into(),
+        }
+    }
+
+    fn escaped(&mut self) -> Result<char, TemplateError> {
+        let column = self.column();
+        self.pos += 1;
+        match self.peek() {
+            Some(c) => {
+                self.pos += 1;
+                Ok(c)
+            }
+            None => Err(TemplateError {
+                column,
+                message: "dangling '\\' at end of template".to_string(),
+            }),
+        }
+    }
+
+    fn segments(&mut self, open_column: Option<usize>) -> Result<Vec<Segment>, TemplateError> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+
+        loop {
+            let Some(c) = self.peek() else {
+                if let Some(column) = open_column {
+                    return Err(TemplateError {
+                        column,
+                        message: "unclosed '['".to_string(),
+                    });
+                }
+                break;
+            };
+
+            match c {
+                '\\' => literal.push(self.escaped()?),
+                '{' | '[' => {
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    if c == '{' {
+                        segments.push(Segment::Placeholder(self.placeholder()?));
+                    } else {
+                        let column = self.column();
+                        self.pos += 1;
+                        let condition = if self.peek() == Some('?') {
+                            Some(self.condition(column)?)
+                        } else {
+                            None
+                        };
+                        segments.push(Segment::Optional {
+                            condition,
+                            segments: self.segments(Some(column))?,
+                        });
+                    }
+                }
+                ']' if open_column.is_some() => {
+                    self.pos += 1;
+                    break;
+                }
+                '}' => return Err(self.error("unmatched '}'")),
+                ']' => return Err(self.error("unmatched ']'")),
+                _ => {
+                    literal.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(segments)
+    }
+
+    fn condition(&mut self, open_column: usize) -> Result<Condition, TemplateError> {
+        self.pos += 1;
+        let column = self.column();
+        let mut text = String::new();
+
+        loop {
+            match self.peek() {
+                None => {
+                    return Err(TemplateError {
+                        column: open_column,
+                        message: "unclosed '['".to_string(),
+                    });
+                }
+                Some('\\') => text.push(self.escaped()?),
+                Some(':') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(c) => {
+                    text.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+
+        let error = |message: String| TemplateError { column, message };
+        let operator_start = text.find(['<', '>', '=', '!']).unwrap_or(text.len());
+        let field = text[..operator_start].trim().to_string();
+
+        if field.is_empty() {
+            return Err(error("condition is missing a placeholder name".to_string()));
+        }
+        if !FIELDS.contains(&field.as_str()) {
+            return Err(error(format!("unknown placeholder '{}'", field)));
+        }
+
+        let rest = &text[operator_start..];
+        if rest.is_empty() {
+            return Ok(Condition {
+                field,
+                comparison: None,
+            });
+        }
+
+        let operators = [
+            (">=", Comparison::GreaterOrEqual),
+            ("<=", Comparison::LessOrEqual),
+            ("!=", Comparison::NotEqual),
+            ("==", Comparison::Equal),
+            (">", Comparison::Greater),
+            ("<", Comparison::Less),
+            ("=", Comparison::Equal),
+        ];
+        let (operator, comparison) = operators
+            .iter()
+            .find(|(operator, _)| rest.starts_with(operator))
+            .ok_or_else(|| error(format!("invalid comparison '{}'", rest)))?;
+        let expected = rest[operator.len()..].trim().to_string();
+
+        if expected.is_empty() {
+            return Err(error(format!(
+                "comparison '{}' is missing a value",
+                operator
+            )));
+        }
+
+        Ok(Condition {
+            field,
+            comparison: Some((*comparison, expected)),
+        })
+    }
+
+    fn quoted(&mut self, item: &mut Item) -> Result<(), TemplateError> {
+        let open_column = self.column();
+        if item.quoted || item.parts.len() > 1 || !item.parts[0].trim().is_empty() {
+            return Err(self.error("unexpected '\"'"));
+        }
+        item.quoted = true;
+        self.pos += 1;
+
+        loop {
+            match self.peek() {
+                None => {
+                    return Err(TemplateError {
+                        column: open_column,
+                        message: "unclosed '\"'".to_string(),
+                    });
+                }
+                Some('\\') => {
+                    let c = self.escaped()?;
+                    item.parts[0].push(c);
+                }
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                Some(c) => {
+                    item.parts[0].push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn placeholder(&mut self) -> Result<Placeholder, TemplateError> {
+        let open_column = self.column();
+        self.pos += 1;
+
+        let new_item = |column| Item {
+            parts: vec![String::new()],
+            quoted: false,
+            column,
+        };
+        let mut items = Vec::new();
+        let mut item = new_item(self.column());
+
+        loop {
+            match self.peek() {
+                None => {
+                    return Err(TemplateError {
+                        column: open_column,
+                        message: "unclosed '{'".to_string(),
+                    });
+                }
+                Some('"') => {
+                    self.quoted(&mut item)?;
+                    while self.peek().is_some_and(char::is_whitespace) {
+                        self.pos += 1;
+                    }
+                    if !matches!(self.peek(), None | Some('|') | Some('}')) {
+                        return Err(self.error("expected '|' or '}' after quoted text"));
+                    }
+                }
+                Some('\\') => {
+                    let c = self.escaped()?;
+                    item.parts.last_mut().unwrap().push(c);
+                }
+                Some('{') => return Err(self.error("unexpected '{' inside placeholder")),
+                Some(':') => {
+                    item.parts.push(String::new());
+                    self.pos += 1;
+                }
+                Some('|') => {
+                    self.pos += 1;
+                    items.push(item);
+                    item = new_item(self.column());
+                }
+                Some('}') => {
+                    self.pos += 1;
+                    items.push(item);
+                    break;
+                }
+                Some(c) => {
+                    item.parts.last_mut().unwrap().push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+
+        let mut alternatives = Vec::new();
+        let mut filters = Vec::new();
+
+        for item in items {
+            let name = item.parts[0].trim();
+            let is_filter = !item.quoted && !alternatives.is_empty() && FILTERS.contains(&name);
+
+            if !is_filter && (item.quoted || FIELDS.contains(&name)) {
+                if !filters.is_empty() {
+                    return Err(TemplateError {
+                        column: item.column,
+                        message: "fallback values must come before filters".to_string(),
+                    });
+                }
+                alternatives.push(Alternative::parse(item)?);
+            } else if alternatives.is_empty() {
+                return Err(TemplateError {
+                    column: if name.is_empty() {
+                        open_column
+                    } else {
+                        item.column
+                    },
+                    message: if name.is_empty() {
+                        "empty placeholder".to_string()
+                    } else {
+                        format!("unknown placeholder '{}'", name)
+                    },
+                });
+            } else {
+                filters.push(Filter::parse(item)?);
+            }
+        }
+
+        Ok(Placeholder {
+            alternatives,
+            filters,
+        })
+    }
+}
+
+impl Alternative {
+    fn parse(item: Item) -> Result<Alternative, TemplateError> {
+        let column = item.column;
+        let mut parts = item.parts.into_iter();
+        let name = parts.next().unwrap_or_default();
+
+        if item.quoted {
+            return Ok(Alternative::Literal(name));
+        }
+
+        let name = name.trim().to_string();
+        let spec = parts.collect::<Vec<_>>();
+        if spec.is_empty() {
+            return Ok(Alternative::Field { name, format: None });
+        }
+
+        let spec = spec.join(":");
+        let format = NumberFormat::parse(&name, spec.trim())
+            .map_err(|message| TemplateError { column, message })?;
+
+        Ok(Alternative::Field {
+            name,
+            format: Some(format),
+        })
+    }
+}
+
+impl NumberFormat {
+    fn parse(field: &str, spec: &str) -> Result<NumberFormat, String> {
+        if !NUMERIC_FIELDS.contains(&field) {
+            return Err(format!(
+                "placeholder '{}' is not numeric and does not take a format",
+                field
+            ));
+        }
+
+        match spec {
+            "%y" | "%Y" if !matches!(field, "year" | "originalyear") => Err(format!(
+                "format '{}' is only supported for 'year' and 'originalyear', not '{}'",
+                spec, field
+            )),
+            "%y" => Ok(NumberFormat::ShortYear),
+            "%Y" => Ok(NumberFormat::FullYear),
+            _ => {
+                let width = spec
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|width| *width <= 16 && spec.chars().all(|c| c.is_ascii_digit()))
+                    .ok_or_else(|| {
+                        format!(
+                            "invalid format '{}' for '{}', expected a width like '02' or '3'",
+                            spec, field
+                        )
+                    })?;
+                Ok(NumberFormat::Pad {
+                    width,
+                    zero: spec.len() > 1 && spec.starts_with('0'),
+                })
+            }
+        }
+    }
+
+    fn apply(&self, number: i64) -> String {
+        match self {
+            NumberFormat::Pad { width, zero: true } => format!("{:0width$}", number, width = width),
+            NumberFormat::Pad { width, zero: false } => format!("{:width$}", number, width = width),
+            NumberFormat::ShortYear => format!("{:02}", number.rem_euclid(100)),
+            NumberFormat::FullYear => format!("{:04}", number),
+        }
+    }
+}
+
+impl Filter {
+    fn parse(item: Item) -> Result<Filter, TemplateError> {
+        let name = item.parts[0].trim();
+        let args = &item.parts[1..];
+        let error = |message: String| TemplateError {
+            column: item.column,
+            message,
+        };
+        let expect_args = |count: usize| {
+            if args.len() == count {
+                Ok(())
+            } else {
+                Err(error(format!(
+                    "filter '{}' expects {} argument(s), got {}",
+                    name,
+                    count,
+                    args.len()
+                )))
+            }
+        };
+
+        let filter = match name {
+            "lower" => Filter::Lower,
+            "upper" => Filter::Upper,
+            "title" => Filter::Title,
+            "initial" => Filter::Initial,
+            "ascii" => Filter::Ascii,
+            "trim" => Filter::Trim,
+            "truncate" => {
+                expect_args(1)?;
+                let length = args[0].trim().parse().map_err(|_| {
+                    error(format!(
+                        "filter 'truncate' expects a length, got '{}'",
+                        args[0]
+                    ))
+                })?;
+                return Ok(Filter::Truncate(length));
+            }
+            "replace" => {
+                expect_args(2)?;
+                return Ok(Filter::Replace(args[0].clone(), args[1].clone()));
+            }
+            "" => return Err(error("empty filter".to_string())),
+            _ => return Err(error(format!("unknown filter '{}'", name))),
+        };
+
+        expect_args(0)?;
+        Ok(filter)
+    }
+
+    fn apply(&self, text: &str) -> String {
+        match self {
+            Filter::Lower => text.to_lowercase(),
+            Filter::Upper => text.to_uppercase(),
+            Filter::Title => title_case(text),
+            Filter::Truncate(length) => text
+                .chars()
+                .take(*length)
+                .collect::<String>()
+                .trim_end()
+                .to_string(),
+            Filter::Initial => initial(text),
+            Filter::Ascii => deunicode::deunicode(text),
+            Filter::Trim => text.trim().to_string(),
+            Filter::Replace(from, to) => {
+                if from.is_empty() {
+                    text.to_string()
+                } else {
+                    text.replace(from.as_str(), to)
+                }
+            }
+        }
+    }
+}
+
+fn format_value(value: Value, format: Option<&NumberFormat>) -> String {
+    match value {
+        Value::Text(text) => text,
+        Value::Number(number) => match format {
+            Some(format) => format.apply(number),
+            None => number.to_string(),
+        },
+    }
+}
+
+fn title_case(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut word_start = true;
+
+    for c in text.chars() {
+        if c.is_whitespace() {
+            word_start = true;
+            result.push(c);
+        } else if word_start {
+            word_start = false;
+            result.extend(c.to_uppercase());
+        } else {
+            result.extend(c.to_lowercase());
+        }
+    }
+
+    result
+}
+
+fn initial(text: &str) -> String {
+    let first = text
+        .chars()
+        .find(|c| c.is_alphanumeric())
+        .map(|c| deunicode::deunicode_char(c).unwrap_or("").to_uppercase());
+
+    match first.and_then(|s| s.chars().next()) {
+        Some(c) if c.is_ascii_alphabetic() => c.to_string(),
+        _ => "#".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, fields: &[(&str, &str)]) -> Option<String> {
+        let resolve = |name: &str| {
+            fields
+                .iter()
+                .find(|(field, _)| *field == name)
+                .map(|(_, value)| match value.parse() {
+                    Ok(number) if NUMERIC_FIELDS.contains(&name) => Value::Number(number),
+                    _ => Value::Text(value.to_string()),
+                })
+        };
+        Template::parse(template)
+            .unwrap()
+            .render(resolve, str::to_string)
+    }
+
+    fn error(template: &str) -> (usize, String) {
+        let error = Template::parse(template).unwrap_err();
+        (error.column, error.message)
+    }
+
+    #[test]
+    fn applies_filters_in_order() {
+        let fields = [("artist", "  Björk Guðmundsdóttir ")];
+        assert_eq!(
+            render("{artist|trim|ascii|upper}", &fields).as_deref(),
+            Some("BJORK GUDMUNDSDOTTIR")
+        );
+        assert_eq!(
+            render("{artist|trim|truncate:6}", &fields).as_deref(),
+            Some("Björk")
+        );
+        assert_eq!(render("{artist|initial}", &fields).as_deref(), Some("B"));
+        assert_eq!(
+            render("{artist|trim|replace:ö:oe|lower}", &fields).as_deref(),
+            Some("bjoerk guðmundsdóttir")
+        );
+        assert_eq!(
+            render("{artist|initial}", &[("artist", "2Pac")]).as_deref(),
+            Some("#")
+        );
+    }
+
+    #[test]
+    fn reports_filter_argument_errors_at_their_column() {
+        assert_eq!(
+            error("{artist|truncate}"),
+            (
+                9,
+                "filter 'truncate' expects 1 argument(s), got 0".to_string()
+            )
+        );
+        assert_eq!(
+            error("{artist|truncate:x}"),
+            (9, "filter 'truncate' expects a length, got 'x'".to_string())
+        );
+        assert_eq!(
+            error("a/{artist|lower:1}"),
+            (
+                11,
+                "filter 'lower' expects 0 argument(s), got 1".to_string()
+            )
+        );
+        assert_eq!(
+            error("{artist|shout}"),
+            (9, "unknown filter 'shout'".to_string())
+        );
+        assert_eq!(
+            error("{nope}"),
+            (2, "unknown placeholder 'nope'".to_string())
+        );
+        assert_eq!(error("x/{artist"), (3, "unclosed '{'".to_string()));
+    }
+
+    #[test]
+    fn falls_back_to_the_first_non_empty_alternative() {
+        let template = "{albumartist|artist|\"Unknown\"|upper}";
+        assert_eq!(
+            render(template, &[("albumartist", " "), ("artist", "Low")]).as_deref(),
+            Some("LOW")
+        );
+        assert_eq!(render(template, &[]).as_deref(), Some("UNKNOWN"));
+        assert_eq!(render("{albumartist|artist}", &[]), None);
+        assert_eq!(
+            error("{artist|upper|\"x\"}"),
+            (15, "fallback values must come before filters".to_string())
+        );
+    }
+
+    #[test]
+    fn renders_optional_and_conditional_sections() {
+        let template = "{album}/[?disctotal>1:Disc {disc}/][{track:02} - ]{title}";
+        let fields = [
+            ("album", "A"),
+            ("title", "T"),
+            ("disc", "2"),
+            ("disctotal", "2"),
+        ];
+        assert_eq!(render(template, &fields).as_deref(), Some("A/Disc 2/T"));
+
+        let fields = [
+            ("album", "A"),
+            ("title", "T"),
+            ("disctotal", "1"),
+            ("track", "3"),
+        ];
+        assert_eq!(render(template, &fields).as_deref(), Some("A/03 - T"));
+
+        assert_eq!(
+            render("[?genre=Jazz:jazz/]x", &[("genre", "Jazz")]).as_deref(),
+            Some("jazz/x")
+        );
+        assert_eq!(
+            render("[?genre!=Jazz:other/]x", &[("genre", "Jazz")]).as_deref(),
+            Some("x")
+        );
+        assert_eq!(
+            error("[?disc>:x]"),
+            (3, "comparison '>' is missing a value".to_string())
+        );
+        assert_eq!(error("a[{title}"), (2, "unclosed '['".to_string()));
+    }
+
+    #[test]
+    fn formats_numbers() {
+        let fields = [("track", "7"), ("year", "1999")];
+        assert_eq!(render("{track:03}", &fields).as_deref(), Some("007"));
+        assert_eq!(render("{track:3}", &fields).as_deref(), Some("  7"));
+        assert_eq!(render("{year:%y}", &fields).as_deref(), Some("99"));
+        assert_eq!(render("{year:%Y}", &fields).as_deref(), Some("1999"));
+        assert_eq!(
+            error("{title:02}"),
+            (
+                2,
+                "placeholder 'title' is not numeric and does not take a format".to_string()
+            )
+        );
+        assert_eq!(
+            error("{track:%y}"),
+            (
+                2,
+                "format '%y' is only supported for 'year' and 'originalyear', not 'track'"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_output() {
+        assert!(Template::check_relative("/{artist}").is_err());
+        assert!(Template::check_relative("C:{artist}").is_err());
+        assert_eq!(
+            Template::check_relative("{artist}/../x")
+                .unwrap_err()
+                .column,
+            10
+        );
+        assert!(Template::check_relative("{artist}/..x").is_ok());
+    }
+
+    #[test]
+    fn title_after_the_first_item_is_the_filter() {
+        let fields = [("artist", "the beatles"), ("title", "Help")];
+        assert_eq!(
+            render("{artist|title}", &fields).as_deref(),
+            Some("The Beatles")
+        );
+        assert_eq!(
+            render("{artist|trim|title}", &[("artist", " the beatles ")]).as_deref(),
+            Some("The Beatles")
+        );
+        assert_eq!(render("{title|upper}", &fields).as_deref(), Some("HELP"));
+        assert_eq!(render("{title|title}", &fields).as_deref(), Some("Help"));
+    }
+}
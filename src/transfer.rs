@@ -0,0 +1,128 @@
+use crate::config::TransferMode;
+
+use std::{fs, io, path::Path};
+
+#[derive(Debug)]
+pub enum TransferOutcome {
+    Done,
+    CopiedInstead(String),
+}
+
+pub fn transfer_file(
+    source_path: &Path,
+    target_path: &Path,
+    mode: TransferMode,
+) -> io::Result<TransferOutcome> {
+    // A target linked to the source is the source, so replacing it would
+    // truncate or delete the file being transferred.
+    if fs::symlink_metadata(target_path).is_ok()
+        && same_file::is_same_file(source_path, target_path).unwrap_or(false)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} is the same file as the source", target_path.display()),
+        ));
+    }
+
+    match mode {
+        TransferMode::Copy => {
+            remove_existing(target_path)?;
+            fs::copy(source_path, target_path)?;
+            Ok(TransferOutcome::Done)
+        }
+        TransferMode::Move => move_file(source_path, target_path),
+        TransferMode::Hardlink => {
+            remove_existing(target_path)?;
+            link_or_copy(source_path, target_path, "hardlink", || {
+                fs::hard_link(source_path, target_path)
+            })
+        }
+        TransferMode::Symlink => {
+            remove_existing(target_path)?;
+            let absolute_source = fs::canonicalize(source_path)?;
+            link_or_copy(source_path, target_path, "symlink", || {
+                create_symlink(&absolute_source, target_path)
+            })
+        }
+        TransferMode::Reflink => {
+            remove_existing(target_path)?;
+            link_or_copy(source_path, target_path, "reflink", || {
+                reflink_copy::reflink(source_path, target_path)
+            })
+        }
+    }
+}
+
+fn move_file(source_path: &Path, target_path: &Path) -> io::Result<TransferOutcome> {
+    match fs::rename(source_path, target_path) {
+        Ok(()) => Ok(TransferOutcome::Done),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(source_path, target_path)?;
+            if let Err(e) = fs::remove_file(source_path) {
+                let _ = fs::remove_file(target_path);
+                return Err(e);
+            }
+            Ok(TransferOutcome::Done)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn link_or_copy(
+    source_path: &Path,
+    target_path: &Path,
+    kind: &str,
+    link: impl FnOnce() -> io::Result<()>,
+) -> io::Result<TransferOutcome> {
+    match link() {
+        Ok(()) => Ok(TransferOutcome::Done),
+        Err(e) => {
+            let _ = fs::remove_file(target_path);
+            fs::copy(source_path, target_path)?;
+            Ok(TransferOutcome::CopiedInstead(format!(
+                "{} not supported ({}), copied instead",
+                kind, e
+            )))
+        }
+    }
+}
+
+fn remove_existing(target_path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(target_path) {
+        Ok(_) => fs::remove_file(target_path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(unix)]
+fn create_symlink(source_path: &Path, target_path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(source_path, target_path)
+}
+
+#[cfg(windows)]
+fn create_symlink(source_path: &Path, target_path: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(source_path, target_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_to_copy_onto_a_link_to_the_source() {
+        let dir = std::env::temp_dir().join(format!("ufrume-transfer-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.wav");
+        let target = dir.join("target.wav");
+        fs::write(&source, b"audio").unwrap();
+        fs::hard_link(&source, &target).unwrap();
+
+        let result = transfer_file(&source, &target, TransferMode::Copy);
+        let contents = fs::read(&source).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(result.is_err());
+        assert_eq!(contents, b"audio");
+    }
+}
@@ -0,0 +1,257 @@
+use crate::{
+    cache::ScanCache,
+    config::Config,
+    infer::{PathPattern, infer_from_path, path_patterns},
+    journal::Journal,
+    organize::{FileResult, Planner, execute_operation},
+    plan::PlannedAction,
+    quarantine::Manifest,
+    scan::{extract_metadata, is_music_file},
+    transfer::TransferOutcome,
+};
+
+use console::style;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    time::{Duration, Instant},
+};
+use walkdir::WalkDir;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+struct PendingFile {
+    last_event: Instant,
+    size: Option<u64>,
+}
+
+pub fn watch_directory(
+    input_dir: &Path,
+    output_dir: &Path,
+    config: &Config,
+    settle: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let input_dir = std::path::absolute(input_dir)?;
+    let output_dir = std::path::absolute(output_dir)?;
+
+    let mut cache = ScanCache::load();
+    let mut planner = Planner::new(&output_dir, config, &mut cache);
+    if let Err(e) = cache.save() {
+        eprintln!("WARNING: Failed to save scan cache: {}", e);
+    }
+
+    let journal = Journal::create(&output_dir)?;
+    let patterns = path_patterns(config);
+
+    // Stop at the next poll on Ctrl+C, so the journal gets finished and an
+    // empty one is not left behind.
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler_stop = Arc::clone(&stop);
+    ctrlc::set_handler(move || handler_stop.store(true, Ordering::SeqCst))?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&input_dir, RecursiveMode::Recursive)?;
+
+    println!(
+        "  Watching {} for new music files (Ctrl+C to stop)",
+        style(input_dir.display()).green()
+    );
+
+    let mut pending: HashMap<PathBuf, PendingFile> = HashMap::new();
+
+    while !stop.load(Ordering::SeqCst) {
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths {
+                        if path.starts_with(&output_dir) {
+                            continue;
+                        }
+                        if path.is_dir() {
+                            for entry in WalkDir::new(&path).into_iter().filter_map(|e| e.ok()) {
+                                track_file(&mut pending, entry.path(), config);
+                            }
+                        } else {
+                            track_file(&mut pending, &path, config);
+                        }
+                    }
+                }
+            }
+            Ok(Err(e)) => eprintln!("  Watch error: {}", e),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        for path in take_settled(&mut pending, settle) {
+            organize_new_file(
+                &path,
+                &input_dir,
+                config,
+                &patterns,
+                &mut planner,
+                &journal,
+                &mut cache,
+            );
+        }
+    }
+
+    if let Some(journal_path) = journal.finish()? {
+        println!("  Journal written to {}", journal_path.display());
+    }
+
+    Ok(())
+}
+
+fn track_file(pending: &mut HashMap<PathBuf, PendingFile>, path: &Path, config: &Config) {
+    if !is_music_file(path, &config.scan.extensions) {
+        return;
+    }
+
+    let size = fs::metadata(path).ok().map(|metadata| metadata.len());
+    pending.insert(
+        path.to_path_buf(),
+        PendingFile {
+            last_event: Instant::now(),
+            size,
+        },
+    );
+}
+
+fn take_settled(pending: &mut HashMap<PathBuf, PendingFile>, settle: Duration) -> Vec<PathBuf> {
+    let mut settled = Vec::new();
+
+    pending.retain(|path, file| {
+        if file.last_event.elapsed() < settle {
+            return true;
+        }
+
+        let size = match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            _ => return false,
+        };
+
+        if file.size == Some(size) {
+            settled.push(path.clone());
+            false
+        } else {
+            file.size = Some(size);
+            file.last_event = Instant::now();
+            true
+        }
+    });
+
+    settled.sort();
+    settled
+}
+
+fn organize_new_file(
+    path: &Path,
+    input_dir: &Path,
+    config: &Config,
+    patterns: &[PathPattern],
+    planner: &mut Planner,
+    journal: &Journal,
+    cache: &mut ScanCache,
+) {
+    let mut metadata = match extract_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            eprintln!(
+                "  Failed to extract metadata from {}: {}",
+                path.display(),
+                e
+            );
+            return;
+        }
+    };
+    infer_from_path(path, input_dir, &mut metadata, patterns, config);
+
+    let plan = planner.plan(&[(path.to_path_buf(), metadata)], cache);
+    let manifest = plan.manifest.as_deref().map(Manifest::new);
+
+    for op in &plan.operations {
+        if let Some(reason) = &op.reason {
+            println!("  {}: {}", op.source.display(), reason);
+        }
+
+        match execute_operation(op, plan.mode, journal) {
+            Ok(FileResult::Moved(outcome)) => {
+                let target = op.action.target().cloned().unwrap_or_default();
+                println!(
+                    "  {} {} -> {}",
+                    style(plan.mode.past_tense()).cyan(),
+                    op.source.display(),
+                    target.display()
+                );
+                if let TransferOutcome::CopiedInstead(reason) = outcome {
+                    println!("  {}: {}", op.source.display(), reason);
+                }
+                if let PlannedAction::Overwrite {
+                    replaces,
+                    set_aside: Some(set_aside),
+                    ..
+                } = &op.action
+                {
+                    println!(
+                        "  {} {} -> {}",
+                        style("set aside").yellow(),
+                        replaces.display(),
+                        set_aside.display()
+                    );
+                }
+                if let Some(manifest) = &manifest
+                    && let Err(e) = manifest.record(op)
+                {
+                    eprintln!("  WARNING: Failed to update quarantine manifest: {}", e);
+                }
+            }
+            Ok(FileResult::Quarantined(outcome)) => {
+                let target = op.action.target().cloned().unwrap_or_default();
+                println!(
+                    "  {} {} -> {}",
+                    style("quarantined").yellow(),
+                    op.source.display(),
+                    target.display()
+                );
+                if let TransferOutcome::CopiedInstead(reason) = outcome {
+                    println!("  {}: {}", op.source.display(), reason);
+                }
+                if let Some(manifest) = &manifest
+                    && let Err(e) = manifest.record(op)
+                {
+                    eprintln!("  WARNING: Failed to update quarantine manifest: {}", e);
+                }
+            }
+            Ok(FileResult::Skipped) => {
+                println!(
+                    "  {} {} (missing metadata)",
+                    style("skipped").dim(),
+                    op.source.display()
+                );
+            }
+            Ok(FileResult::Duplicate) => {
+                let duplicate_of = match &op.action {
+                    PlannedAction::Duplicate { duplicate_of } => duplicate_of.display().to_string(),
+                    _ => String::new(),
+                };
+                println!(
+                    "  {} {} (duplicate of {})",
+                    style("duplicate").yellow(),
+                    op.source.display(),
+                    duplicate_of
+                );
+            }
+            Err(e) => {
+                eprintln!("  Failed to organize {}: {}", op.source.display(), e);
+            }
+        }
+    }
+}